@@ -5,18 +5,23 @@ extern crate serde;
 extern crate serde_json;
 
 use std::cmp;
+use std::mem;
 use std::io::{Read, Write};
 use std::fs::File;
 use std::error::Error;
+use std::collections::{HashMap, HashSet, VecDeque};
 
 use tcod::console::*;
 use tcod::colors::{self, Color};
 use tcod::map::{Map as FovMap, FovAlgorithm};
-use tcod::input::{self, Event, Mouse, Key};
+use tcod::input::{self, Event, Mouse, Key, KeyCode};
+use tcod::line::Line;
 
 use rand::Rng;
 use rand::distributions::{Weighted, WeightedChoice, IndependentSample};
 
+use serde::de::DeserializeOwned;
+
 
 const SCREEN_WIDTH: i32 = 80;
 const SCREEN_HEIGHT: i32 = 50;
@@ -33,54 +38,204 @@ const PANEL_Y: i32 = SCREEN_HEIGHT - PANEL_HEIGHT;
 const MSG_X: i32 = BAR_WIDTH + 2;
 const MSG_WIDTH: i32 = SCREEN_WIDTH - BAR_WIDTH - 2;
 const MSG_HEIGHT: usize = PANEL_HEIGHT as usize - 1;
+// how many messages the history keeps
+const MAX_MESSAGES: usize = 500;
 
 const COLOR_DARK_WALL: Color = Color { r: 0, g: 0, b: 100 };
 const COLOR_LIGHT_WALL: Color = Color { r: 130, g: 110, b: 50 };
 const COLOR_DARK_GROUND: Color = Color { r: 50, g: 50, b: 150 };
 const COLOR_LIGHT_GROUND: Color = Color { r: 200, g: 180, b: 50 };
 
+// while aiming: tiles out of range are darkened by this, the area of effect
+// and the cursor painted over
+const COLOR_OUT_OF_RANGE: Color = Color { r: 110, g: 110, b: 110 };
+const COLOR_AREA_OF_EFFECT: Color = Color { r: 220, g: 100, b: 30 };
+const COLOR_CURSOR: Color = Color { r: 80, g: 160, b: 255 };
+
 const ROOM_MAX_SIZE: i32 = 10;
 const ROOM_MIN_SIZE: i32 = 6;
 const MAX_ROOMS: i32 = 5;
 
-const POTION_HEAL_AMOUNT:i32 = 40;
-const LIGHTNING_RANGE: i32 = 5;
-const LIGHTNING_DAMAGE: i32 = 40;
-const CONFUSE_RANGE: i32 = 8;
-const CONFUSE_NUM_TURMS: i32 = 10;
-const FIREBALL_RADIUS: i32 = 3;
-const FIREBALL_DAMAGE: i32 = 25;
-
 const INVENTORY_WIDTH: i32 = 50;
 const CHARACTER_SCREEN_WIDTH: i32 = 30;
 
 const FOV_ALGO: FovAlgorithm = FovAlgorithm::Basic;
 const FOV_LIGHT_WALLS: bool = true;
 const TORCH_RADIUS: i32 = 10;
+// monsters see about as far as the player's torch reaches
+const MONSTER_SIGHT: f32 = 10.0;
 
 const PLAYER: usize = 0; // player will always be the first object
 
-const LEVEL_UP_BASE: i32 = 200;
-const LEVEL_UP_FACTOR: i32 = 150;
+// the eight steps to a neighbouring tile
+const DIRECTIONS: &[(i32, i32)] = &[(-1, -1), (0, -1), (1, -1), (-1, 0), (1, 0), (-1, 1), (0, 1), (1, 1)];
+
 const LEVEL_SCREEN_WIDTH: i32 = 40;
 
+// how far things can be thrown, and how much a thrown item hurts
+const THROW_RANGE: f32 = 6.0;
+const THROW_DAMAGE: i32 = 2;
+
+// what the player is told when asked to aim at something
+const TARGET_TILE_HELP: &str =
+	"Aim with the mouse or the movement keys, Tab for the next enemy. Enter or click to confirm, Escape to cancel.";
+const TARGET_MONSTER_HELP: &str =
+	"Pick an enemy with the mouse, the movement keys or Tab. Enter or click to confirm, Escape to cancel.";
+
+// the bottom of the dungeon, where the boss guards the artifact the player came for
+const FINAL_LEVEL: u32 = 10;
+const FINAL_BOSS: &str = "Great Spookie";
+const ARTIFACT: &str = "Spookie Orb";
+const VICTORY_SCREEN_WIDTH: i32 = 50;
+
+// unique monsters are kept at least this far from the player when they turn up
+const UNIQUE_MIN_DISTANCE: f32 = 10.0;
+
+// the most spells the player can know, one for each letter of the spell menu
+const MAX_SPELLS: usize = 26;
+
+// the player gets a point of mana back every this many turns
+const MANA_REGEN_TURNS: u32 = 4;
+// and some hit points every this many, more at higher levels
+const HP_REGEN_TURNS: u32 = 5;
+const HP_REGEN_LEVELS: i32 = 3;
+
+// the player's food clock, one point is used up every turn
+const START_NUTRITION: i32 = 1000;
+const MAX_NUTRITION: i32 = 2000;
+const HUNGRY_AT: i32 = 300;
+const WEAK_AT: i32 = 100;
+const FAINTING_AT: i32 = 0;
+const STARVED_AT: i32 = -200;
+// chance per turn of fainting while starving, and how many turns it takes
+const FAINT_CHANCE: u32 = 10;
+const FAINT_TURNS: i32 = 3;
+// corpses feed this much per max HP of the monster, and can be rotten
+const CORPSE_KIND: &str = "corpse";
+const CORPSE_NUTRITION: i32 = 10;
+const CORPSE_SICK_CHANCE: u32 = 20;
+const FOOD_POISONING_DAMAGE: i32 = 8;
+
+// gold lying around is an item of this kind, counted into the purse when picked up
+const GOLD_KIND: &str = "gold piece";
+// chance of a room having some gold in it
+const GOLD_CHANCE: u32 = 30;
+// chance of a level having a shop, and how well stocked it is
+const SHOP_CHANCE: u32 = 40;
+const SHOP_STOCK_CHANCE: u32 = 30;
+const SHOPKEEPER_GOLD: i32 = 300;
+
+// what unidentified potions and scrolls look like, shuffled every game
+const POTION_LOOKS: &[&str] = &[
+	"murky", "bubbly", "fizzy", "smoky", "cloudy", "milky", "golden", "pink",
+	"black", "swirly", "glowing", "oily", "brown", "puce", "effervescent", "sky blue",
+];
+const WAND_LOOKS: &[&str] = &[
+	"oak", "ebony", "glass", "iron", "copper", "bone", "crystal", "jade",
+	"marble", "silver", "brass", "ivory", "pine", "tin", "runed", "twisted",
+];
+const SCROLL_SYLLABLES: &[&str] = &[
+	"ZELGO", "MER", "XYZZY", "FOOBIE", "BLETCH", "ELBIB", "YLOH", "VERR", "YED",
+	"HORRE", "VENZAR", "NHOL", "KERNOD", "ELAM", "ANDOVA", "GARVEN", "THARR", "JUYED",
+];
+
+const MONSTERS_FILE: &str = "data/monsters.json";
+const ITEMS_FILE: &str = "data/items.json";
+const DIALOGUE_FILE: &str = "data/dialogue.json";
+const QUESTS_FILE: &str = "data/quests.json";
+const CLASSES_FILE: &str = "data/classes.json";
+const SPELLS_FILE: &str = "data/spells.json";
+const PROGRESSION_FILE: &str = "data/progression.json";
+// the player's own key bindings, on top of the defaults
+const KEYS_FILE: &str = "keys.json";
+// where finished runs are written down
+const RECORDS_FILE: &str = "records.txt";
+const JOURNAL_WIDTH: i32 = 50;
+const CLASS_SCREEN_WIDTH: i32 = 50;
+const PERK_SCREEN_WIDTH: i32 = 50;
+const HELP_SCREEN_WIDTH: i32 = 50;
+const DIALOGUE_WIDTH: i32 = 50;
+
+// how far along a unique monster is, each is only ever met once
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+enum UniqueFate {
+	Spawned,
+	Seen,
+	Slain,
+}
+
+// something that takes the player several turns, and carries on by itself
+// until it's done or something gets in the way
+#[derive(Clone, Debug, PartialEq)]
+enum Activity {
+	// until healed
+	Resting,
+	// walking to the nearest place not seen yet, remembering the items already
+	// noticed so it doesn't stop for them again
+	Exploring { seen: Vec<(i32, i32)> },
+	// walking the shortest way to a tile
+	Travelling { to: (i32, i32) },
+}
+
 #[derive(Clone, Copy, Debug, PartialEq)]
 enum PlayerAction {
 	TookTurn,
 	DidntTakeTurn,
-	Exit
+	Exit,
+	// left the dungeon with the artifact
+	Won,
 }
 
 type Map = Vec<Vec<Tile>>;
-type Messages = Vec<(String, Color)>;
+
+#[derive(Serialize, Deserialize)]
+struct Message {
+	text: String,
+	color: Color,
+	// how many times in a row it was said
+	count: u32,
+}
+
+impl Message {
+	fn text(&self) -> String {
+		if self.count > 1 {
+			format!("{} x{}", self.text, self.count)
+		} else {
+			self.text.clone()
+		}
+	}
+}
+
+#[derive(Serialize, Deserialize)]
+struct Messages {
+	messages: Vec<Message>,
+}
+
+impl Messages {
+	fn new() -> Self {
+		Messages { messages: vec![] }
+	}
+}
 
 trait MessageLog {
 	fn add<T: Into<String>>(&mut self, message: T, color: Color);
 }
 
-impl MessageLog for Vec<(String, Color)> {
+impl MessageLog for Messages {
 	fn add<T: Into<String>>(&mut self, message: T, color: Color) {
-		self.push((message.into(), color));
+		let text = message.into();
+		if let Some(last) = self.messages.last_mut() {
+			if last.text == text && last.color == color {
+				last.count += 1;
+				return;
+			}
+		}
+		self.messages.push(Message { text, color, count: 1 });
+		// only the most recent ones are kept, so saves don't keep growing
+		if self.messages.len() > MAX_MESSAGES {
+			let excess = self.messages.len() - MAX_MESSAGES;
+			self.messages.drain(..excess);
+		}
 	}
 }
 
@@ -90,17 +245,189 @@ struct Tcod {
 	panel: Offscreen,
 	fov: FovMap,
 	mouse: Mouse,
+	data: Data,
+	keymap: Keymap,
+}
+
+// game content loaded from the data directory at startup
+struct Data {
+	monsters: Vec<MonsterTemplate>,
+	items: Vec<ItemTemplate>,
+	dialogues: Vec<Dialogue>,
+	quests: Vec<QuestTemplate>,
+	classes: Vec<ClassTemplate>,
+	spells: Vec<Spell>,
+	progression: Progression,
 }
 
 #[derive(Serialize, Deserialize)]
 struct Game {
 	map: Map,
 	log: Messages,
-	inventory: Vec<Object>,
 	dungeon_level: u32,
+	// item kinds that look different every game, and which of them the player knows
+	appearances: HashMap<String, Appearance>,
+	identified: HashSet<String>,
+	// names the player gave to item kinds they haven't identified
+	called: HashMap<String, String>,
+	// things that have happened, set and checked by dialogue
+	flags: HashSet<String>,
+	quests: Vec<Quest>,
+	// the unique monsters that have turned up so far this run
+	uniques: HashMap<String, UniqueFate>,
+	// the player's class, and how long until each of its skills can be used again
+	class: String,
+	cooldowns: HashMap<String, i32>,
+	// names of the spells the player knows
+	spells: Vec<String>,
+	perks: Vec<Perk>,
+	// what the player is busy doing over several turns, this isn't saved
+	#[serde(skip)]
+	activity: Option<Activity>,
+	// whether exploring picks up the items it comes across
+	auto_pickup: bool,
+	// for the summary at the end of the run
+	turns: u32,
+	kills: u32,
+	deepest_level: u32,
+}
+
+impl Game {
+	// Learn what a kind of item is. Returns false if there was nothing new to learn.
+	fn identify(&mut self, kind: &str) -> bool {
+		self.appearances.contains_key(kind) && self.identified.insert(kind.to_string())
+	}
+
+	fn is_identified(&self, kind: &str) -> bool {
+		!self.appearances.contains_key(kind) || self.identified.contains(kind)
+	}
+
+	// move any quests along that this counts towards
+	fn quest_event(&mut self, event: QuestEvent) {
+		for quest in self.quests.iter_mut().filter(|quest| !quest.completed) {
+			match (&quest.objective, &event) {
+				(Objective::Kill { monster, .. }, &QuestEvent::Killed(name)) if monster == name => {
+					quest.progress += 1;
+				}
+				(Objective::Retrieve { item }, &QuestEvent::PickedUp(kind)) if item == kind => {
+					quest.progress = 1;
+				}
+				(&Objective::ReachDepth { .. }, &QuestEvent::Reached(level)) => {
+					quest.progress = cmp::max(quest.progress, level as i32);
+				}
+				_ => {}
+			}
+		}
+	}
+
+	fn quest(&self, name: &str) -> Option<&Quest> {
+		self.quests.iter().find(|quest| quest.name == name)
+	}
+
+	fn has_perk(&self, name: &str) -> bool {
+		self.perks.iter().any(|perk| perk.name == name)
+	}
+
+	// percent chance of the player getting a second blow in
+	fn double_strike_chance(&self) -> u32 {
+		self.perks.iter().map(|perk| match perk.bonus {
+			PerkBonus::DoubleStrike { chance } => chance,
+			_ => 0,
+		}).sum()
+	}
+
+	// how much damage of this kind the player takes after resistances
+	fn resist(&self, kind: DamageKind, damage: i32) -> i32 {
+		let percent: i32 = self.perks.iter().map(|perk| match perk.bonus {
+			PerkBonus::Resist { kind: resisted, percent } if resisted == kind => percent,
+			_ => 0,
+		}).sum();
+		damage * (100 - cmp::min(percent, 100)) / 100
+	}
+
+	// every quick regeneration perk halves the time between recovering points
+	fn regen_turns(&self, turns: u32) -> u32 {
+		let halvings = self.perks.iter().filter(|perk| perk.bonus == PerkBonus::QuickRegen).count();
+		cmp::max(1, turns >> halvings)
+	}
+}
+
+// a quest the player has taken on, copied from its template when started
+#[derive(Clone, Debug, Serialize, Deserialize)]
+struct Quest {
+	name: String,
+	description: String,
+	objective: Objective,
+	reward: Reward,
+	progress: i32,
+	completed: bool,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+enum Objective {
+	Kill { monster: String, count: i32 },
+	Retrieve { item: String },
+	ReachDepth { level: u32 },
+}
+
+impl Objective {
+	fn goal(&self) -> i32 {
+		match *self {
+			Objective::Kill { count, .. } => count,
+			Objective::Retrieve { .. } => 1,
+			Objective::ReachDepth { level } => level as i32,
+		}
+	}
+
+	fn describe(&self) -> String {
+		match *self {
+			Objective::Kill { ref monster, count } => format!("kill {} x{}", monster, count),
+			Objective::Retrieve { ref item } => format!("find a {}", item),
+			Objective::ReachDepth { level } => format!("reach dungeon level {}", level),
+		}
+	}
+}
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+struct Reward {
+	#[serde(default)]
+	xp: i32,
+	#[serde(default)]
+	gold: i32,
+	#[serde(default)]
+	items: Vec<String>,
+}
+
+// things that happen in the game that quests can be waiting for
+enum QuestEvent<'a> {
+	Killed(&'a str),
+	PickedUp(&'a str),
+	Reached(u32),
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+struct Appearance {
+	name: String,
+	plural: String,
 }
 
 fn main() {
+	// refuse to start with broken data files rather than crash mid-game
+	let data = match load_data() {
+		Ok(data) => data,
+		Err(e) => {
+			eprintln!("Could not load game data: {}", e);
+			std::process::exit(1);
+		}
+	};
+	let keymap = match load_keymap() {
+		Ok(keymap) => keymap,
+		Err(e) => {
+			eprintln!("Could not load key bindings: {}", e);
+			std::process::exit(1);
+		}
+	};
+
 	// window setup
 	let root = Root::initializer()
 		.font("arial10x10.png", FontLayout::Tcod)
@@ -111,34 +438,121 @@ fn main() {
 	tcod::system::set_fps(LIMIT_FPS);
 
 	let mut tcod = Tcod {
-		root: root,
+		root,
 		con: Offscreen::new(MAP_WIDTH, MAP_HEIGHT),
 		panel: Offscreen::new(SCREEN_WIDTH, PANEL_HEIGHT),
 		fov: FovMap::new(MAP_WIDTH, MAP_HEIGHT),
 		mouse: Default::default(),
+		data,
+		keymap,
 	};
 
 	main_menu(&mut tcod);
 }
 
-fn new_game(tcod: &mut Tcod) -> (Vec<Object> , Game) {
+// None if the player backs out of choosing a class
+fn new_game(tcod: &mut Tcod) -> Option<(Vec<Object> , Game)> {
+	let class_id = choose_class(tcod)?;
+	let class = &tcod.data.classes[class_id];
 	// create object representing the player
-	let player = create_player();
+	let player = create_player(class, &tcod.data);
 
 	// the list of objects with just the player
 	let mut objects = vec![player];
 	let mut game = Game {
-		map: make_map(&mut objects, 1) ,
-		log: vec![],
-		inventory: vec![],
+		map: make_map(&mut objects, 1, &tcod.data),
+		log: Messages::new(),
 		dungeon_level: 1,
+		appearances: random_appearances(&tcod.data),
+		identified: HashSet::new(),
+		called: HashMap::new(),
+		flags: HashSet::new(),
+		quests: vec![],
+		uniques: HashMap::new(),
+		class: class.name.clone(),
+		cooldowns: HashMap::new(),
+		spells: vec![],
+		perks: vec![],
+		activity: None,
+		auto_pickup: false,
+		turns: 0,
+		kills: 0,
+		deepest_level: 1,
 	};
+	place_uniques(&mut objects, &mut game, &tcod.data);
+	// you know what you packed
+	for item in &class.items {
+		game.identify(item);
+	}
 
 	initialise_fov(&game.map, tcod);
 
 	game.log.add("Welcome stranger! Be careful of spookies", colors::RED);	
+	learn_class_spells(1, &mut game, &tcod.data);
 
-	(objects, game)
+	Some((objects, game))
+}
+
+fn choose_class(tcod: &mut Tcod) -> Option<usize> {
+	let options = tcod.data.classes.iter()
+		.map(|class| format!("{}: {}", class.name, class.description))
+		.collect::<Vec<_>>();
+	menu("Choose your class:\n", &options, CLASS_SCREEN_WIDTH, &mut tcod.root)
+}
+
+// give each kind of potion and scroll a random look for this game
+fn random_appearances(data: &Data) -> HashMap<String, Appearance> {
+	let mut rng = rand::thread_rng();
+	let mut potion_looks = POTION_LOOKS.to_vec();
+	rng.shuffle(&mut potion_looks);
+	let mut wand_looks = WAND_LOOKS.to_vec();
+	rng.shuffle(&mut wand_looks);
+
+	let mut appearances = HashMap::new();
+	let mut labels = HashSet::new();
+	for template in &data.items {
+		let appearance = match template.class {
+			Some(ItemClass::Potion) => {
+				// there are enough looks for every potion, checked on load
+				let look = potion_looks.pop().unwrap();
+				Appearance {
+					name: format!("{} potion", look),
+					plural: format!("{} potions", look),
+				}
+			}
+			Some(ItemClass::Scroll) => {
+				let mut label = String::new();
+				while label.is_empty() || labels.contains(&label) {
+					let words = (0..rng.gen_range(1, 3))
+						.map(|_| *rng.choose(SCROLL_SYLLABLES).unwrap())
+						.collect::<Vec<_>>();
+					label = words.join(" ");
+				}
+				labels.insert(label.clone());
+				Appearance {
+					name: format!("scroll labelled {}", label),
+					plural: format!("scrolls labelled {}", label),
+				}
+			}
+			Some(ItemClass::Wand) => {
+				let look = wand_looks.pop().unwrap();
+				Appearance {
+					name: format!("{} wand", look),
+					plural: format!("{} wands", look),
+				}
+			}
+			Some(ItemClass::Staff) => {
+				let look = wand_looks.pop().unwrap();
+				Appearance {
+					name: format!("{} staff", look),
+					plural: format!("{} staves", look),
+				}
+			}
+			None => continue,
+		};
+		appearances.insert(template.name.clone(), appearance);
+	}
+	appearances
 }
 
 fn initialise_fov(map: &Map, tcod: &mut Tcod) {
@@ -157,17 +571,30 @@ fn initialise_fov(map: &Map, tcod: &mut Tcod) {
 
 fn play_game(objects: &mut Vec<Object>, game: &mut Game, tcod: &mut Tcod) {
 	let mut previous_player_position = (-1, -1);
-	let mut key: Key = Default::default();
-
 	while !tcod.root.window_closed() {
+		// the mouse keeps its last state, so a click only counts on the frame it happens
+		let mut key: Key = Default::default();
+		let mut clicked = false;
 		match input::check_for_event(input::MOUSE | input::KEY_PRESS) {
-			Some((_, Event::Mouse(m))) => tcod.mouse = m,
+			Some((_, Event::Mouse(m))) => {
+				tcod.mouse = m;
+				clicked = m.lbutton_pressed;
+			}
 			Some((_, Event::Key(k))) => key = k,
-			_ => key = Default::default(),
+			_ => {}
 		}
 
 		let fov_recompute = previous_player_position != (objects[PLAYER].x, objects[PLAYER].y);
-		render_all(tcod, game, &objects, fov_recompute);
+		render_all(tcod, game, objects, fov_recompute);
+		announce_uniques(objects, game, tcod);
+
+		// with the view up to date, a hostile in it stops whatever the player is busy with
+		if game.activity.is_some() {
+			if let Some(enemy_id) = enemy_in_view(objects, tcod) {
+				game.log.add(format!("You stop: a {} comes into view.", objects[enemy_id].name), colors::LIGHT_RED);
+				game.activity = None;
+			}
+		}
 
 		tcod.root.flush();
 		level_up(objects, game, tcod);
@@ -178,78 +605,482 @@ fn play_game(objects: &mut Vec<Object>, game: &mut Game, tcod: &mut Tcod) {
 		}
 
 		previous_player_position = objects[PLAYER].pos();
-		let player_action = handle_keys(key, tcod, game, objects);
+		let was_alive = objects[PLAYER].alive;
+		let player_action = if game.activity.is_some() {
+			if key.code == KeyCode::NoKey && !clicked {
+				carry_on(objects, game, tcod)
+			} else {
+				// any key press or click stops what the player is busy with
+				game.activity = None;
+				PlayerAction::DidntTakeTurn
+			}
+		} else if clicked {
+			let to = (tcod.mouse.cx as i32, tcod.mouse.cy as i32);
+			travel_to(to, objects, game, tcod)
+		} else {
+			handle_keys(key, tcod, game, objects)
+		};
+		let hp = objects[PLAYER].fighter.map_or(0, |f| f.hp);
 		if player_action == PlayerAction::Exit {
 			save_game(objects, game).unwrap();
 			break
 		}
+		if player_action == PlayerAction::Won {
+			victory(objects, game, tcod);
+			break
+		}
+		if player_action == PlayerAction::TookTurn {
+			game.turns += 1;
+			for cooldown in game.cooldowns.values_mut() {
+				*cooldown -= 1;
+			}
+			game.cooldowns.retain(|_, &mut cooldown| cooldown > 0);
+			regenerate(&mut objects[PLAYER], game);
+			if objects[PLAYER].confused > 0 {
+				objects[PLAYER].confused -= 1;
+				if objects[PLAYER].confused == 0 {
+					game.log.add("You are no longer confused.", colors::LIGHT_GREEN);
+				}
+			}
+		}
 
 		if objects[PLAYER].alive && player_action == PlayerAction::TookTurn {
-			for id in 0..objects.len() {
-				if objects[id].ai.is_some() {
-					ai_take_turn(id, objects, &tcod.fov, game);
+			// a player who faints gives the monsters a few free turns
+			check_for_theft(objects, game);
+			let turns = 1 + digest(&mut objects[PLAYER], game);
+			for _ in 0..turns {
+				// monsters picking things up shrink the list, so it can't be a range loop
+				let mut id = 0;
+				while id < objects.len() {
+					if objects[id].ai.is_some() {
+						id = ai_take_turn(id, objects, &tcod.fov, game, &tcod.data);
+					}
+					id += 1;
+				}
+			}
+		}
+		drop_loot(objects);
+		complete_quests(objects, game, &tcod.data);
+
+		// getting hurt is worth stopping for, whatever did it
+		if game.activity.is_some() && objects[PLAYER].fighter.map_or(0, |f| f.hp) < hp {
+			game.log.add("You stop: you are hurt!", colors::LIGHT_RED);
+			game.activity = None;
+		}
+
+		if was_alive && !objects[PLAYER].alive {
+			let outcome = format!("Died on dungeon level {}", game.dungeon_level);
+			record_run(&outcome, objects, game);
+		}
+	}
+}
+
+// slowly get back hit points and mana, as long as the player isn't starving
+// a player this hungry no longer heals over time
+fn too_hungry_to_heal(player: &Object) -> bool {
+	matches!(player.hunger(), Hunger::Weak | Hunger::Fainting)
+}
+
+fn regenerate(player: &mut Object, game: &Game) {
+	if too_hungry_to_heal(player) {
+		return;
+	}
+	if game.turns.is_multiple_of(game.regen_turns(HP_REGEN_TURNS)) {
+		let amount = 1 + player.level / HP_REGEN_LEVELS;
+		player.heal(amount);
+	}
+	if game.turns.is_multiple_of(game.regen_turns(MANA_REGEN_TURNS)) {
+		player.mana = cmp::min(player.mana + 1, player.max_mana);
+	}
+}
+
+// take the next turn of whatever the player is busy with
+fn carry_on(objects: &mut Vec<Object>, game: &mut Game, tcod: &mut Tcod) -> PlayerAction {
+	let activity = match game.activity.clone() {
+		Some(activity) => activity,
+		None => return PlayerAction::DidntTakeTurn,
+	};
+	match activity {
+		Activity::Resting => {
+			if is_rested(&objects[PLAYER]) {
+				game.log.add("You feel rested.", colors::LIGHT_VIOLET);
+				game.activity = None;
+				PlayerAction::DidntTakeTurn
+			} else if too_hungry_to_heal(&objects[PLAYER]) {
+				game.log.add("You stop resting: you are too hungry to recover.", colors::YELLOW);
+				game.activity = None;
+				PlayerAction::DidntTakeTurn
+			} else {
+				PlayerAction::TookTurn
+			}
+		}
+		Activity::Exploring { mut seen } => {
+			let player_pos = objects[PLAYER].pos();
+			let new_items: Vec<usize> = (0..objects.len())
+				.filter(|&id| {
+					let object = &objects[id];
+					worth_picking_up(object) && tcod.fov.is_in_fov(object.x, object.y) &&
+						!seen.contains(&object.pos())
+				})
+				.collect();
+			if !game.auto_pickup {
+				if let Some(&item_id) = new_items.first() {
+					game.log.add(format!("You stop: you spot an item ({}).", objects[item_id].display_name(game)),
+					             colors::LIGHT_GREEN);
+					game.activity = None;
+					return PlayerAction::DidntTakeTurn;
+				}
+			}
+			if let Some(&item_id) = new_items.iter().find(|&&id| objects[id].pos() == player_pos) {
+				let count = objects.len();
+				pick_item_up(item_id, PLAYER, objects, game);
+				// don't keep trying to pick up what doesn't fit
+				if objects.len() == count {
+					seen.push(player_pos);
+				}
+				game.activity = Some(Activity::Exploring { seen });
+				return PlayerAction::DidntTakeTurn;
+			}
+			let targets: Vec<(i32, i32)> = new_items.iter().map(|&id| objects[id].pos()).collect();
+			let step = first_step(player_pos, &game.map, |x, y| {
+				targets.contains(&(x, y)) || !game.map[x as usize][y as usize].explored
+			});
+			match step {
+				Some((dx, dy)) if is_blocked(player_pos.0 + dx, player_pos.1 + dy, &game.map, objects) => {
+					game.log.add("You stop: something is in the way.", colors::WHITE);
+					game.activity = None;
+					PlayerAction::DidntTakeTurn
+				}
+				Some((dx, dy)) => {
+					move_by(PLAYER, dx, dy, &game.map, objects);
+					game.activity = Some(Activity::Exploring { seen });
+					PlayerAction::TookTurn
+				}
+				None => {
+					game.log.add("There is nothing left to explore.", colors::WHITE);
+					game.activity = None;
+					PlayerAction::DidntTakeTurn
+				}
+			}
+		}
+		Activity::Travelling { to } => {
+			let player_pos = objects[PLAYER].pos();
+			if player_pos == to {
+				game.activity = None;
+				return PlayerAction::DidntTakeTurn;
+			}
+			match first_step(player_pos, &game.map, |x, y| (x, y) == to) {
+				Some((dx, dy)) if !is_blocked(player_pos.0 + dx, player_pos.1 + dy, &game.map, objects) => {
+					move_by(PLAYER, dx, dy, &game.map, objects);
+					PlayerAction::TookTurn
+				}
+				Some(_) => {
+					game.log.add("You stop: something is in the way.", colors::WHITE);
+					game.activity = None;
+					PlayerAction::DidntTakeTurn
+				}
+				None => {
+					game.log.add("You can't find a way there any more.", colors::WHITE);
+					game.activity = None;
+					PlayerAction::DidntTakeTurn
+				}
+			}
+		}
+	}
+}
+
+// Sets off walking to an explored tile, if there's a known way there
+fn travel_to(to: (i32, i32), objects: &[Object], game: &mut Game, tcod: &Tcod) -> PlayerAction {
+	let (x, y) = to;
+	if x < 0 || y < 0 || x >= MAP_WIDTH || y >= MAP_HEIGHT || to == objects[PLAYER].pos() ||
+		!game.map[x as usize][y as usize].explored || game.map[x as usize][y as usize].blocked {
+		return PlayerAction::DidntTakeTurn;
+	}
+	if enemy_in_view(objects, tcod).is_some() {
+		game.log.add("You can't travel with enemies nearby!", colors::RED);
+	} else if first_step(objects[PLAYER].pos(), &game.map, |x, y| (x, y) == to).is_none() {
+		game.log.add("You don't know a way there.", colors::WHITE);
+	} else {
+		game.activity = Some(Activity::Travelling { to });
+	}
+	PlayerAction::DidntTakeTurn
+}
+
+// Searches outwards from a tile through the explored part of the map for the
+// nearest tile is_goal accepts, and returns the first step of the way there.
+fn first_step<F: Fn(i32, i32) -> bool>(from: (i32, i32), map: &Map, is_goal: F) -> Option<(i32, i32)> {
+	let mut came_from = vec![vec![None; MAP_HEIGHT as usize]; MAP_WIDTH as usize];
+	came_from[from.0 as usize][from.1 as usize] = Some(from);
+	let mut queue = VecDeque::new();
+	queue.push_back(from);
+	while let Some((x, y)) = queue.pop_front() {
+		if (x, y) != from {
+			if is_goal(x, y) {
+				// retrace the way back to the step next to the start
+				let mut step = (x, y);
+				while let Some(previous) = came_from[step.0 as usize][step.1 as usize] {
+					if previous == from {
+						return Some((step.0 - from.0, step.1 - from.1));
+					}
+					step = previous;
+				}
+			}
+			// unexplored tiles can be walked to, but not through
+			if !map[x as usize][y as usize].explored {
+				continue;
+			}
+		}
+		for dx in -1..2 {
+			for dy in -1..2 {
+				let (next_x, next_y) = (x + dx, y + dy);
+				if next_x < 0 || next_y < 0 || next_x >= MAP_WIDTH || next_y >= MAP_HEIGHT {
+					continue;
+				}
+				let next = &mut came_from[next_x as usize][next_y as usize];
+				if next.is_none() && !map[next_x as usize][next_y as usize].blocked &&
+					!cuts_corner(x, y, dx, dy, map) {
+					*next = Some((x, y));
+					queue.push_back((next_x, next_y));
 				}
 			}
 		}
 	}
+	None
+}
+
+// corpses aren't worth carrying around, and shop goods have to be paid for first
+fn worth_picking_up(object: &Object) -> bool {
+	object.item.as_ref().is_some_and(|item| item.kind != CORPSE_KIND && !item.unpaid)
+}
+
+fn is_rested(player: &Object) -> bool {
+	player.fighter.is_none_or(|f| f.hp >= player.max_hp()) && player.mana >= player.max_mana
+}
+
+// any monster in sight that's out to get the player
+fn enemy_in_view(objects: &[Object], tcod: &Tcod) -> Option<usize> {
+	objects.iter().position(|object| {
+		object.alive && object.ai.is_some() && objects[PLAYER].faction.is_hostile_to(object.faction) &&
+			tcod.fov.is_in_fov(object.x, object.y)
+	})
+}
+
+fn run_summary(objects: &[Object], game: &Game) -> String {
+	let player = &objects[PLAYER];
+	let completed = game.quests.iter().filter(|quest| quest.completed).count();
+	let uniques = game.uniques.values().filter(|&&fate| fate == UniqueFate::Slain).count();
+	format!("Level {} {}, {} xp, {} gold. Reached dungeon level {}, slew {} monsters \
+	         ({} of them unique), completed {} quests in {} turns.",
+	        player.level, game.class, player.fighter.map_or(0, |f| f.xp), player.gold, game.deepest_level,
+	        game.kills, uniques, completed, game.turns)
+}
+
+// add a line about the finished run to the records file
+fn record_run(outcome: &str, objects: &[Object], game: &Game) {
+	let line = format!("{}. {}\n", outcome, run_summary(objects, game));
+	let file = std::fs::OpenOptions::new().create(true).append(true).open(RECORDS_FILE);
+	if let Err(e) = file.and_then(|mut file| file.write_all(line.as_bytes())) {
+		eprintln!("Could not write to {}: {}", RECORDS_FILE, e);
+	}
+}
+
+fn victory(objects: &[Object], game: &Game, tcod: &mut Tcod) {
+	let outcome = format!("Escaped the dungeon with the {}", ARTIFACT);
+	record_run(&outcome, objects, game);
+	// the run is over, there is nothing to continue
+	let _ = std::fs::remove_file("savegame");
+	let text = format!("\nVictory!\n\nYou escaped the dungeon with the {}.\n\n{}\n",
+	                   ARTIFACT, run_summary(objects, game));
+	msgbox(&text, VICTORY_SCREEN_WIDTH, &mut tcod.root);
+}
+
+// hand out the rewards for any quest whose objective has been met
+fn complete_quests(objects: &mut Vec<Object>, game: &mut Game, data: &Data) {
+	let mut rewards = vec![];
+	for quest in game.quests.iter_mut() {
+		if !quest.completed && quest.progress >= quest.objective.goal() {
+			quest.completed = true;
+			rewards.push((quest.name.clone(), quest.reward.clone()));
+		}
+	}
+	for (name, reward) in rewards {
+		game.log.add(format!("Quest complete: {}!", name), colors::LIGHT_YELLOW);
+		if reward.xp > 0 {
+			game.log.add(format!("You gain {} experience points.", reward.xp), colors::LIGHT_YELLOW);
+			objects[PLAYER].fighter.as_mut().unwrap().xp += reward.xp;
+		}
+		if reward.gold > 0 {
+			game.log.add(format!("You receive {} gold.", reward.gold), colors::GOLD);
+			objects[PLAYER].gold += reward.gold;
+		}
+		for name in &reward.items {
+			let template = data.items.iter().find(|item| &item.name == name).expect("quests are checked on load");
+			give_item(template, objects, game);
+		}
+	}
 }
 
 fn next_level(tcod: &mut Tcod, objects: &mut Vec<Object>, game: &mut Game) {
 	game.log.add("You take a moment to rest, and recover your strength.", colors::VIOLET);
-	let heal_hp = objects[PLAYER].fighter.map_or(0, |f| f.max_hp / 2);
+	let heal_hp = objects[PLAYER].max_hp() / 2;
 	objects[PLAYER].heal(heal_hp);
 
     game.log.add("After a rare moment of peace, you descend deeper into \
                   the heart of the dungeon...", colors::RED);
     game.dungeon_level += 1;
-    game.map = make_map(objects, game.dungeon_level);
+	let followers = gather_followers(objects);
+    game.map = make_map(objects, game.dungeon_level, &tcod.data);
+	place_followers(followers, objects, game);
+	place_uniques(objects, game, &tcod.data);
  	initialise_fov(&game.map, tcod);
+	let level = game.dungeon_level;
+	game.deepest_level = cmp::max(game.deepest_level, level);
+	game.quest_event(QuestEvent::Reached(level));
+}
+
+// climb back up with the artifact, arriving on the level's stairs down
+fn previous_level(tcod: &mut Tcod, objects: &mut Vec<Object>, game: &mut Game) {
+	game.log.add("You climb back up towards the daylight...", colors::LIGHT_YELLOW);
+	game.dungeon_level -= 1;
+	let followers = gather_followers(objects);
+	game.map = make_map(objects, game.dungeon_level, &tcod.data);
+	let stairs = objects.iter().find(|object| object.name == "stairs down").map(|object| object.pos());
+	if let Some((x, y)) = stairs {
+		objects[PLAYER].set_pos(x, y);
+	}
+	place_followers(followers, objects, game);
+	place_uniques(objects, game, &tcod.data);
+	initialise_fov(&game.map, tcod);
+}
+
+// allies standing next to the player come along when the player takes the stairs
+fn gather_followers(objects: &mut Vec<Object>) -> Vec<Object> {
+	let mut followers = vec![];
+	let mut id = objects.len();
+	while id > PLAYER + 1 {
+		id -= 1;
+		if objects[id].alive && objects[id].faction == Faction::Player &&
+			objects[id].distance_to(&objects[PLAYER]) < 2.0 {
+			followers.push(objects.remove(id));
+		}
+	}
+	followers
+}
+
+// put the followers down as close to the player as there's room on the new level
+fn place_followers(followers: Vec<Object>, objects: &mut Vec<Object>, game: &mut Game) {
+	let (x, y) = objects[PLAYER].pos();
+	for mut follower in followers {
+		let mut spots = vec![];
+		for spot_x in cmp::max(0, x - 2)..cmp::min(MAP_WIDTH, x + 3) {
+			for spot_y in cmp::max(0, y - 2)..cmp::min(MAP_HEIGHT, y + 3) {
+				if !is_blocked(spot_x, spot_y, &game.map, objects) {
+					spots.push((spot_x, spot_y));
+				}
+			}
+		}
+		spots.sort_by_key(|&(spot_x, spot_y)| cmp::max((spot_x - x).abs(), (spot_y - y).abs()));
+		match spots.first() {
+			Some(&(spot_x, spot_y)) => {
+				follower.set_pos(spot_x, spot_y);
+				game.log.add(format!("The {} follows you.", follower.name), colors::LIGHT_GREEN);
+				objects.push(follower);
+			}
+			None => game.log.add(format!("There's no room for the {} to follow you.", follower.name), colors::LIGHT_GREY),
+		}
+	}
+}
+
+fn carries_artifact(object: &Object) -> bool {
+	object.inventory.iter().any(|item| item.item.as_ref().is_some_and(|item| item.kind == ARTIFACT))
+}
+
+// Each unique monster turns up once per run, on one of its levels. The chance
+// goes up level by level, so by its deepest one it's sure to be there.
+fn place_uniques(objects: &mut Vec<Object>, game: &mut Game, data: &Data) {
+	let level = game.dungeon_level as i32;
+	for template in &data.monsters {
+		let depths = match template.unique {
+			Some(ref depths) if depths.min <= level && level <= depths.max => depths,
+			_ => continue,
+		};
+		if game.uniques.contains_key(&template.name) || rand::thread_rng().gen_range(0, depths.max - level + 1) != 0 {
+			continue;
+		}
+		if let Some((x, y)) = lair(objects, &game.map) {
+			objects.push(create_monster(x, y, template, game.dungeon_level, data));
+			game.uniques.insert(template.name.clone(), UniqueFate::Spawned);
+		}
+	}
+}
+
+// a random free tile well away from the player
+fn lair(objects: &[Object], map: &Map) -> Option<(i32, i32)> {
+	for _ in 0..100 {
+		let x = rand::thread_rng().gen_range(0, MAP_WIDTH);
+		let y = rand::thread_rng().gen_range(0, MAP_HEIGHT);
+		if !is_blocked(x, y, map, objects) && objects[PLAYER].distance(x, y) >= UNIQUE_MIN_DISTANCE {
+			return Some((x, y));
+		}
+	}
+	None
+}
+
+// the first sight of a unique monster gets a mention
+fn announce_uniques(objects: &[Object], game: &mut Game, tcod: &Tcod) {
+	for object in objects.iter().filter(|object| object.alive && tcod.fov.is_in_fov(object.x, object.y)) {
+		if game.uniques.get(&object.name) != Some(&UniqueFate::Spawned) {
+			continue;
+		}
+		let announcement = tcod.data.monsters.iter()
+			.find(|template| template.name == object.name)
+			.and_then(|template| template.announcement.clone())
+			.unwrap_or_else(|| format!("You have found {}!", object.name));
+		game.log.add(announcement, colors::LIGHT_RED);
+		game.uniques.insert(object.name.clone(), UniqueFate::Seen);
+	}
 }
 
 fn handle_keys(key: Key, tcod: &mut Tcod, game: &mut Game, objects: &mut Vec<Object>) -> PlayerAction {
-	use tcod::input::Key;
-	use tcod::input::KeyCode::*;
 	use PlayerAction::*;
 
 	let player_alive = objects[PLAYER].alive;
 
-	match (key, player_alive) {
-		(Key { code: Enter, alt: true, .. }, _) => {
+	match (tcod.keymap.command(key), player_alive) {
+		(Some(Command::Fullscreen), _) => {
 			let fullscreen = tcod.root.is_fullscreen();
 			tcod.root.set_fullscreen(!fullscreen);
 			DidntTakeTurn
 		},
-		(Key { code: Escape, .. }, _) => return Exit, // exit game
-		// movement keys
-		(Key { code: Up, .. }, true) => {
-			player_move_or_attack(0, -1, objects, game);
-			TookTurn
-		},
-		(Key { code: Down, .. }, true) => {
-			player_move_or_attack(0, 1, objects, game);
-			TookTurn
-		},
-		(Key { code: Left, .. }, true) => {
-			player_move_or_attack(-1, 0, objects, game);
-			TookTurn
-		},
-		(Key { code: Right, .. }, true) => {
-			player_move_or_attack(1, 0, objects, game);
-			TookTurn
+		(Some(Command::SaveAndQuit), _) => Exit, // exit game
+		(Some(Command::Help), _) => {
+			let help = tcod.keymap.help();
+			msgbox(&help, HELP_SCREEN_WIDTH, &mut tcod.root);
+			DidntTakeTurn
+		}
+		// movement keys, or waiting a turn where you are
+		(Some(command), true) if command.direction().is_some() => {
+			let (dx, dy) = command.direction().unwrap();
+			if (dx, dy) == (0, 0) || player_move_or_attack(dx, dy, objects, game, tcod) {
+				TookTurn
+			} else {
+				DidntTakeTurn
+			}
 		},
-		(Key { printable: 'g', ..}, true) => {
+		(Some(Command::PickUp), true) => {
 			// pick up an item
 			let item_id = objects.iter().position(|object| {
 				object.pos() == objects[PLAYER].pos() && object.item.is_some()
 			});
 			if let Some(item_id) = item_id {
-				pick_item_up(item_id, objects, game);
+				pick_item_up(item_id, PLAYER, objects, game);
 			}
 			DidntTakeTurn
 		},
-		(Key { printable: 'i', ..}, true) => {
+		(Some(Command::Inventory), true) => {
 			let inventory_index = inventory_menu(
-				&game.inventory,
+				&objects[PLAYER].inventory,
+				game,
 				"Press the key next to an item to use it, or any other to cancel.\n",
 				&mut tcod.root);
 			if let Some(inventory_index) = inventory_index {
@@ -257,80 +1088,663 @@ fn handle_keys(key: Key, tcod: &mut Tcod, game: &mut Game, objects: &mut Vec<Obj
 			}
 			DidntTakeTurn
 		},
-		(Key { printable: 'd', ..}, true) => {
+		(Some(Command::Drop), true) => {
 			let inventory_index = inventory_menu(
-				&game.inventory,
+				&objects[PLAYER].inventory,
+				game,
 				"Press the key next to an item to drop it, or any other to cancel.\n",
 				&mut tcod.root);
 			if let Some(inventory_index) = inventory_index {
-				drop_item(inventory_index, objects, game);
+				let count = objects[PLAYER].inventory[inventory_index].item.as_ref().map_or(1, |item| item.count);
+				let amount = if count > 1 {
+					ask_quantity("Drop how many?", count, &mut tcod.root)
+				} else {
+					Some(1)
+				};
+				if let Some(amount) = amount {
+					drop_item(inventory_index, amount, objects, game);
+				}
 			}
 			DidntTakeTurn
 		}
-		(Key { printable: '<', .. }, true) => {
-			// go down stairs if player is on them
-			let player_on_stairs = objects.iter().any(|object| {
-				object.pos() == objects[PLAYER].pos() && object.name == "stairs"
-			});
-
-			if player_on_stairs {
-				next_level(tcod, objects, game);
+		(Some(Command::Throw), true) => {
+			let inventory_index = inventory_menu(
+				&objects[PLAYER].inventory,
+				game,
+				"Press the key next to an item to throw it, or any other to cancel.\n",
+				&mut tcod.root);
+			if let Some(inventory_index) = inventory_index {
+				game.log.add(TARGET_TILE_HELP, colors::LIGHT_CYAN);
+				if let Some(target) = target_tile(tcod, game, objects, Some(THROW_RANGE), 0) {
+					let missile = split_stack(inventory_index, 1, &mut objects[PLAYER].inventory);
+					// a thrown weapon hurts more
+					let power = THROW_DAMAGE + missile.equipment.map_or(0, |e| e.power_bonus);
+					launch(missile, target, power, objects, game, &tcod.data);
+					return TookTurn;
+				}
 			}
 			DidntTakeTurn
 		}
-		(Key { printable: 'c', .. }, true) => {
-			let player = &objects[PLAYER];
-			let level = player.level;
-			let level_up_xp = LEVEL_UP_BASE + player.level * LEVEL_UP_FACTOR;
-			if let Some(fighter) = player.fighter.as_ref() {
-				let msg = format!("Character information
-
-Level: {}
-Exp: {}
-Exp to level up: {}
-Max HP: {}
-Attack: {}
-Defense: {}", level, fighter.xp, level_up_xp, fighter.max_hp, player.power(game), fighter.defense);
-				msgbox(&msg, CHARACTER_SCREEN_WIDTH, &mut tcod.root);
+		(Some(Command::Fire), true) => {
+			if fire_launcher(objects, game, tcod) {
+				TookTurn
+			} else {
+				DidntTakeTurn
 			}
-
-			DidntTakeTurn
 		}
-		_ => DidntTakeTurn,
-	}
-}
-
+		(Some(Command::CallItem), true) => {
+			// give an unidentified kind of item a name of our own
+			let inventory_index = inventory_menu(
+				&objects[PLAYER].inventory,
+				game,
+				"Press the key next to an item to call it something, or any other to cancel.\n",
+				&mut tcod.root);
+			if let Some(inventory_index) = inventory_index {
+				let kind = objects[PLAYER].inventory[inventory_index].item.as_ref().map(|item| item.kind.clone());
+				match kind {
+					Some(ref kind) if !game.is_identified(kind) => {
+						let name = input_text("Call it:", INVENTORY_WIDTH, &mut tcod.root);
+						match name {
+							Some(ref name) if name.trim().is_empty() => {
+								game.called.remove(kind);
+							}
+							Some(name) => {
+								game.called.insert(kind.clone(), name.trim().to_string());
+							}
+							None => {}
+						}
+					}
+					_ => {
+						game.log.add("You already know what that is.", colors::WHITE);
+					}
+				}
+			}
+			DidntTakeTurn
+		}
+		(Some(Command::Rest), true) => {
+			if is_rested(&objects[PLAYER]) {
+				game.log.add("You don't need to rest.", colors::WHITE);
+			} else if too_hungry_to_heal(&objects[PLAYER]) {
+				game.log.add("You are too hungry to rest.", colors::YELLOW);
+			} else if enemy_in_view(objects, tcod).is_some() {
+				game.log.add("You can't rest with enemies nearby!", colors::RED);
+			} else {
+				game.log.add("You sit down to rest.", colors::WHITE);
+				game.activity = Some(Activity::Resting);
+			}
+			DidntTakeTurn
+		}
+		(Some(Command::Explore), true) => {
+			if enemy_in_view(objects, tcod).is_some() {
+				game.log.add("You can't explore with enemies nearby!", colors::RED);
+			} else {
+				// whatever is in sight already won't stop the player, though it's
+				// still collected when picking things up
+				let seen = if game.auto_pickup {
+					vec![]
+				} else {
+					objects.iter()
+						.filter(|object| worth_picking_up(object) && tcod.fov.is_in_fov(object.x, object.y))
+						.map(|object| object.pos())
+						.collect()
+				};
+				game.activity = Some(Activity::Exploring { seen });
+			}
+			DidntTakeTurn
+		}
+		(Some(Command::TravelToStairs), true) => {
+			// the way out once the artifact is found, otherwise the way down
+			let name = if carries_artifact(&objects[PLAYER]) { "stairs up" } else { "stairs down" };
+			let stairs = objects.iter()
+				.find(|object| object.name == name && game.map[object.x as usize][object.y as usize].explored)
+				.map(|object| object.pos());
+			match stairs {
+				Some(stairs) => travel_to(stairs, objects, game, tcod),
+				None => {
+					game.log.add(format!("You haven't found the {} yet.", name), colors::WHITE);
+					DidntTakeTurn
+				}
+			}
+		}
+		(Some(Command::AutoPickup), _) => {
+			game.auto_pickup = !game.auto_pickup;
+			let state = if game.auto_pickup { "on" } else { "off" };
+			game.log.add(format!("Picking up items while exploring is now {}.", state), colors::WHITE);
+			DidntTakeTurn
+		}
+		(Some(Command::Descend), true) => {
+			// go down stairs if player is on them
+			if standing_on(objects, "stairs down") {
+				next_level(tcod, objects, game);
+			} else {
+				game.log.add("There are no stairs down here.", colors::WHITE);
+			}
+			DidntTakeTurn
+		}
+		(Some(Command::Ascend), true) => {
+			if !standing_on(objects, "stairs up") {
+				game.log.add("There are no stairs up here.", colors::WHITE);
+			} else if !carries_artifact(&objects[PLAYER]) {
+				game.log.add(format!("You came here for the {}, and you won't leave without it.", ARTIFACT), colors::WHITE);
+			} else if game.dungeon_level == 1 {
+				return Won;
+			} else {
+				previous_level(tcod, objects, game);
+			}
+			DidntTakeTurn
+		}
+		(Some(Command::Journal), _) => {
+			show_journal(game, &mut tcod.root);
+			DidntTakeTurn
+		}
+		(Some(Command::Look), _) => {
+			game.log.add("Move the cursor to look around, Escape to stop.", colors::LIGHT_CYAN);
+			target_tile(tcod, game, objects, None, 0);
+			DidntTakeTurn
+		}
+		(Some(Command::MessageHistory), _) => {
+			show_message_history(game, &tcod.keymap, &mut tcod.root);
+			DidntTakeTurn
+		}
+		(Some(Command::UseSkill), true) => {
+			if use_skill(objects, game, tcod) {
+				TookTurn
+			} else {
+				DidntTakeTurn
+			}
+		}
+		(Some(Command::CastSpell), true) => {
+			if cast_spell(objects, game, tcod) {
+				TookTurn
+			} else {
+				DidntTakeTurn
+			}
+		}
+		(Some(Command::Character), true) => {
+			let player = &objects[PLAYER];
+			let level = player.level;
+			let level_up_xp = tcod.data.progression.xp.to_next_level(level);
+			let perks = game.perks.iter().map(|perk| perk.name.as_str()).collect::<Vec<_>>().join(", ");
+			if let Some(fighter) = player.fighter.as_ref() {
+				let msg = format!("Character information
+
+Class: {}
+Level: {}
+Exp: {}
+Exp to level up: {}
+Max HP: {}
+Mana: {}/{}
+Attack: {}
+Defense: {}
+Perks: {}", game.class, level, fighter.xp, level_up_xp, player.max_hp(), player.mana, player.max_mana,
+				                  player.power(), player.defense(), if perks.is_empty() { "none" } else { &perks });
+				msgbox(&msg, CHARACTER_SCREEN_WIDTH, &mut tcod.root);
+			}
+
+			DidntTakeTurn
+		}
+		_ => DidntTakeTurn,
+	}
+}
+
+fn standing_on(objects: &[Object], name: &str) -> bool {
+	objects.iter().any(|object| object.pos() == objects[PLAYER].pos() && object.name == name)
+}
+
+// Everything the player can do from the keyboard, whichever keys it's bound to
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Deserialize)]
+enum Command {
+	North,
+	South,
+	West,
+	East,
+	NorthWest,
+	NorthEast,
+	SouthWest,
+	SouthEast,
+	Wait,
+	PickUp,
+	Inventory,
+	Drop,
+	Throw,
+	Fire,
+	CallItem,
+	UseSkill,
+	CastSpell,
+	Rest,
+	Explore,
+	TravelToStairs,
+	AutoPickup,
+	Descend,
+	Ascend,
+	Journal,
+	MessageHistory,
+	Look,
+	Character,
+	Help,
+	Fullscreen,
+	SaveAndQuit,
+}
+
+// Every command with its default keys, in the order the help screen lists
+// them. Movement works with the arrow keys, the numpad and the vi-keys:
+//   y k u    7 8 9
+//   h . l    4 5 6
+//   b j n    1 2 3
+const DEFAULT_KEYS: &[(Command, &[&str])] = &[
+	(Command::North, &["Up", "NumPad8", "k"]),
+	(Command::South, &["Down", "NumPad2", "j"]),
+	(Command::West, &["Left", "NumPad4", "h"]),
+	(Command::East, &["Right", "NumPad6", "l"]),
+	(Command::NorthWest, &["NumPad7", "y"]),
+	(Command::NorthEast, &["NumPad9", "u"]),
+	(Command::SouthWest, &["NumPad1", "b"]),
+	(Command::SouthEast, &["NumPad3", "n"]),
+	(Command::Wait, &["NumPad5", "."]),
+	(Command::PickUp, &["g"]),
+	(Command::Inventory, &["i"]),
+	(Command::Drop, &["d"]),
+	(Command::Throw, &["t"]),
+	(Command::Fire, &["f"]),
+	(Command::CallItem, &["C"]),
+	(Command::UseSkill, &["a"]),
+	(Command::CastSpell, &["m"]),
+	(Command::Rest, &["z", "R"]),
+	(Command::Explore, &["o"]),
+	(Command::TravelToStairs, &["G"]),
+	(Command::AutoPickup, &["P"]),
+	(Command::Descend, &[">"]),
+	(Command::Ascend, &["<"]),
+	(Command::Journal, &["q"]),
+	(Command::MessageHistory, &["M"]),
+	(Command::Look, &["x"]),
+	(Command::Character, &["c"]),
+	(Command::Help, &["?"]),
+	(Command::Fullscreen, &["Alt+Enter"]),
+	(Command::SaveAndQuit, &["Escape"]),
+];
+
+impl Command {
+	// the step it takes, or (0, 0) for waiting a turn
+	fn direction(self) -> Option<(i32, i32)> {
+		use Command::*;
+		match self {
+			North => Some((0, -1)),
+			South => Some((0, 1)),
+			West => Some((-1, 0)),
+			East => Some((1, 0)),
+			NorthWest => Some((-1, -1)),
+			NorthEast => Some((1, -1)),
+			SouthWest => Some((-1, 1)),
+			SouthEast => Some((1, 1)),
+			Wait => Some((0, 0)),
+			_ => None,
+		}
+	}
+
+	fn describe(self) -> &'static str {
+		use Command::*;
+		match self {
+			North => "Move up",
+			South => "Move down",
+			West => "Move left",
+			East => "Move right",
+			NorthWest => "Move up and left",
+			NorthEast => "Move up and right",
+			SouthWest => "Move down and left",
+			SouthEast => "Move down and right",
+			Wait => "Wait a turn",
+			PickUp => "Pick up",
+			Inventory => "Use an item",
+			Drop => "Drop an item",
+			Throw => "Throw an item",
+			Fire => "Fire a bow or sling",
+			CallItem => "Name an unknown item",
+			UseSkill => "Use a skill",
+			CastSpell => "Cast a spell",
+			Rest => "Rest until healed",
+			Explore => "Explore the level",
+			TravelToStairs => "Travel to the stairs",
+			AutoPickup => "Toggle picking up items while exploring",
+			Descend => "Go down stairs",
+			Ascend => "Go up stairs",
+			Journal => "Quest journal",
+			MessageHistory => "Message history",
+			Look => "Look around",
+			Character => "Character information",
+			Help => "This help",
+			Fullscreen => "Toggle fullscreen",
+			SaveAndQuit => "Save and quit",
+		}
+	}
+}
+
+// A key as written in the key bindings: a single character like "g" or ">",
+// or the name of a special key like "Up" or "NumPad5", optionally "Alt+" one
+#[derive(Clone, Debug)]
+struct KeySpec {
+	name: String,
+	code: KeyCode,
+	printable: char,
+	alt: bool,
+}
+
+const KEY_NAMES: &[(&str, KeyCode)] = &[
+	("Up", KeyCode::Up), ("Down", KeyCode::Down), ("Left", KeyCode::Left), ("Right", KeyCode::Right),
+	("Enter", KeyCode::Enter), ("Escape", KeyCode::Escape), ("Tab", KeyCode::Tab),
+	("Space", KeyCode::Spacebar), ("Backspace", KeyCode::Backspace), ("Delete", KeyCode::Delete),
+	("Insert", KeyCode::Insert), ("Home", KeyCode::Home), ("End", KeyCode::End),
+	("PageUp", KeyCode::PageUp), ("PageDown", KeyCode::PageDown),
+	("NumPad0", KeyCode::NumPad0), ("NumPad1", KeyCode::NumPad1), ("NumPad2", KeyCode::NumPad2),
+	("NumPad3", KeyCode::NumPad3), ("NumPad4", KeyCode::NumPad4), ("NumPad5", KeyCode::NumPad5),
+	("NumPad6", KeyCode::NumPad6), ("NumPad7", KeyCode::NumPad7), ("NumPad8", KeyCode::NumPad8),
+	("NumPad9", KeyCode::NumPad9), ("NumPadEnter", KeyCode::NumPadEnter),
+	("F1", KeyCode::F1), ("F2", KeyCode::F2), ("F3", KeyCode::F3), ("F4", KeyCode::F4),
+	("F5", KeyCode::F5), ("F6", KeyCode::F6), ("F7", KeyCode::F7), ("F8", KeyCode::F8),
+	("F9", KeyCode::F9), ("F10", KeyCode::F10), ("F11", KeyCode::F11), ("F12", KeyCode::F12),
+];
+
+impl KeySpec {
+	fn parse(name: &str) -> Result<KeySpec, String> {
+		let (alt, key) = match name.strip_prefix("Alt+") {
+			Some(key) => (true, key),
+			None => (false, name),
+		};
+		let mut chars = key.chars();
+		let (code, printable) = match (chars.next(), chars.next()) {
+			(Some(c), None) if !c.is_whitespace() => (KeyCode::Char, c),
+			_ => match KEY_NAMES.iter().find(|&&(key_name, _)| key_name == key) {
+				Some(&(_, code)) => (code, '\0'),
+				None => return Err(format!("unknown key \"{}\"", name)),
+			},
+		};
+		Ok(KeySpec { name: name.into(), code, printable, alt })
+	}
+
+	fn matches(&self, key: Key) -> bool {
+		let same_key = match self.code {
+			KeyCode::Char => key.printable == self.printable,
+			code => key.code == code,
+		};
+		same_key && key.alt == self.alt
+	}
+
+	fn same_as(&self, other: &KeySpec) -> bool {
+		self.code == other.code && self.printable == other.printable && self.alt == other.alt
+	}
+}
+
+struct Keymap {
+	bindings: Vec<(Command, Vec<KeySpec>)>,
+}
+
+impl Keymap {
+	fn command(&self, key: Key) -> Option<Command> {
+		self.bindings.iter()
+			.find(|&(_, keys)| keys.iter().any(|spec| spec.matches(key)))
+			.map(|&(command, _)| command)
+	}
+
+	// the keys for everything, as shown on the help screen
+	fn help(&self) -> String {
+		let mut help = String::from("Keys\n\n");
+		for &(command, ref keys) in &self.bindings {
+			let names = keys.iter().map(|spec| spec.name.as_str()).collect::<Vec<_>>();
+			let names = if names.is_empty() { "(unbound)".into() } else { names.join(", ") };
+			help.push_str(&format!("{}: {}\n", command.describe(), names));
+		}
+		help
+	}
+}
+
+// The default keys, with any the player has rebound in the keys file taking
+// their place. A key bound to two different commands is an error.
+fn load_keymap() -> Result<Keymap, String> {
+	let overrides = if std::path::Path::new(KEYS_FILE).exists() {
+		load_json::<HashMap<Command, Vec<String>>>(KEYS_FILE)?
+	} else {
+		HashMap::new()
+	};
+
+	let mut bindings = vec![];
+	for &(command, defaults) in DEFAULT_KEYS {
+		let names = match overrides.get(&command) {
+			Some(names) => names.iter().map(String::as_str).collect::<Vec<_>>(),
+			None => defaults.to_vec(),
+		};
+		let keys = names.iter()
+			.map(|name| KeySpec::parse(name))
+			.collect::<Result<Vec<_>, _>>()
+			.map_err(|e| format!("{}: {:?}: {}", KEYS_FILE, command, e))?;
+		bindings.push((command, keys));
+	}
+
+	for (index, &(command, ref keys)) in bindings.iter().enumerate() {
+		for &(other_command, ref other_keys) in &bindings[index + 1..] {
+			if let Some(spec) = keys.iter().find(|spec| other_keys.iter().any(|other| spec.same_as(other))) {
+				return Err(format!("{}: \"{}\" is bound to both {:?} and {:?}", KEYS_FILE, spec.name, command, other_command));
+			}
+		}
+	}
+	Ok(Keymap { bindings })
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+struct Item {
+	// name of the template the item was made from
+	kind: String,
+	targeting: Targeting,
+	effects: Vec<Effect>,
+	message: Option<String>,
+	stackable: bool,
+	// how many are in the stack, and what to call more than one
+	count: i32,
+	plural: String,
+	// uses left in a wand or staff
+	charges: Option<i32>,
+	class: Option<ItemClass>,
+	// what kind of launcher this can be shot from
+	ammo: Option<Ammo>,
+	// what one is worth in a shop, and whether it still belongs to the shop
+	price: i32,
+	unpaid: bool,
+}
+
+// how the thing being used picks what it is used on
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+enum Targeting {
+	#[default]
+	User,
+	ClosestMonster { range: i32 },
+	Monster { range: Option<f32> },
+	Tile { range: Option<f32> },
+	// something else in the user's inventory
+	InventoryItem,
+}
+
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+enum Effect {
+	Heal { amount: i32 },
+	// a radius of 0 only hits the target itself
+	Damage { amount: i32, kind: DamageKind, radius: i32 },
+	Status { status: Status, turns: i32, radius: i32 },
+	Identify,
+	// add charges to a wand or staff
+	Recharge { amount: i32 },
+	// bring monsters of the named kind to fight on the user's side
+	Summon { monster: String, count: i32 },
+	// food, which has a percent chance of making the eater sick instead
+	Nourish {
+		amount: i32,
+		#[serde(default)]
+		sick_chance: u32,
+	},
+	// add a spell to the player's spellbook
+	LearnSpell { spell: String },
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+enum ItemClass {
+	Potion,
+	Scroll,
+	Wand,
+	Staff,
+}
+
 #[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
-enum Item {
-	Heal,
+enum DamageKind {
+	Physical,
+	Fire,
 	Lightning,
-	Confuse,
-	Fireball,
-	Equipment,
+	Cold,
+}
+
+impl DamageKind {
+	fn verb(self) -> &'static str {
+		match self {
+			DamageKind::Physical => "is hit",
+			DamageKind::Fire => "gets burned",
+			DamageKind::Lightning => "is electrocuted",
+			DamageKind::Cold => "is frozen",
+		}
+	}
 }
 
 #[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
-enum Monster {
-	Orc,
-	Troll,
+enum Status {
+	Confused,
 }
 
-fn pick_item_up(object_id: usize, objects: &mut Vec<Object>, game: &mut Game) {
-	if game.inventory.len() >= 26 {
-		game.log.add(format!("Inventory is full, cannot pick up {}", objects[object_id].name), colors::RED);
+// something a monster does instead of attacking, now and then
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+struct Ability {
+	// percent chance of using it on a turn it could
+	chance: u32,
+	// User is the monster itself, anything else aims at the enemy it's after
+	targeting: Targeting,
+	effects: Vec<Effect>,
+	message: String,
+}
+
+// Moves an item from the floor into the picker's inventory. Returns the
+// picker's index, which shifts down if the item was before it in the list.
+fn pick_item_up(object_id: usize, picker_id: usize, objects: &mut Vec<Object>, game: &mut Game) -> usize {
+	// gold goes in the purse rather than the inventory
+	if objects[object_id].item.as_ref().is_some_and(|item| item.kind == GOLD_KIND) {
+		let gold = objects.remove(object_id);
+		let picker_id = if object_id < picker_id { picker_id - 1 } else { picker_id };
+		let amount = gold.item.map_or(0, |item| item.count);
+		if picker_id == PLAYER {
+			game.log.add(format!("You picked up {} gold.", amount), colors::GOLD);
+		} else {
+			game.log.add(format!("{} picks up some gold.", objects[picker_id].name), colors::LIGHT_GREY);
+		}
+		objects[picker_id].gold += amount;
+		return picker_id;
+	}
+
+	let stacks = objects[picker_id].inventory.iter().any(|item| item.stacks_with(&objects[object_id]));
+	if objects[picker_id].inventory.len() >= 26 && !stacks {
+		if picker_id == PLAYER {
+			game.log.add(format!("Inventory is full, cannot pick up {}", objects[object_id].display_name(game)), colors::RED);
+		}
+		return picker_id;
+	}
+
+	let item = objects.remove(object_id);
+	let picker_id = if object_id < picker_id { picker_id - 1 } else { picker_id };
+	if picker_id == PLAYER {
+		game.log.add(format!("You picked up {}!", item.display_name(game)), colors::GREEN);
 	} else {
-		let item = objects.swap_remove(object_id);
-		game.log.add(format!("You picked up {}!", item.name), colors::GREEN);
-		let index = game.inventory.len();
-		let slot = item.equipment.map(|e| e.slot);
-		game.inventory.push(item);
+		game.log.add(format!("{} picks up {}.", objects[picker_id].name, item.display_name(game)), colors::LIGHT_GREY);
+	}
+	if picker_id == PLAYER {
+		if let Some(ref item) = item.item {
+			game.quest_event(QuestEvent::PickedUp(&item.kind));
+			if item.kind == ARTIFACT && !carries_artifact(&objects[PLAYER]) {
+				game.log.add("The dungeon shudders. Now get out alive!", colors::LIGHT_YELLOW);
+			}
+		}
+	}
+	let equipment = item.equipment;
+	let picker = &mut objects[picker_id];
+	let index = add_to_inventory(item, &mut picker.inventory);
+
+	if let Some(equipment) = equipment {
+		if slot_is_free(equipment, &picker.inventory) {
+			if picker_id == PLAYER {
+				equip_item(index, &mut picker.inventory, &mut game.log);
+			} else {
+				// monsters gear up without announcing it
+				equip_item(index, &mut picker.inventory, &mut Messages::new());
+			}
+		}
+	}
+	picker_id
+}
 
-		if let Some(slot) = slot {
-			if get_equipped_in_slot(slot, &game.inventory).is_none() {
-				game.inventory[index].equip(&mut game.log);
+// dead monsters leave everything they carried on the floor
+fn drop_loot(objects: &mut Vec<Object>) {
+	let mut loot = vec![];
+	for object in objects.iter_mut().skip(PLAYER + 1) {
+		if !object.alive && object.gold > 0 {
+			loot.push(gold_pile(object.x, object.y, object.gold));
+			object.gold = 0;
+		}
+		if !object.alive && !object.inventory.is_empty() {
+			for mut item in object.inventory.drain(..) {
+				if let Some(ref mut equipment) = item.equipment {
+					equipment.equipped = false;
+				}
+				item.set_pos(object.x, object.y);
+				loot.push(item);
 			}
 		}
 	}
+	for item in loot {
+		place_on_floor(item, objects);
+	}
+}
+
+// Put an item in an inventory, merging it into a matching stack if there is
+// one. Returns where it ended up.
+fn add_to_inventory(item: Object, inventory: &mut Vec<Object>) -> usize {
+	match inventory.iter().position(|other| other.stacks_with(&item)) {
+		Some(stack) => {
+			merge_stack(&mut inventory[stack], item);
+			stack
+		}
+		None => {
+			inventory.push(item);
+			inventory.len() - 1
+		}
+	}
+}
+
+// leave an item on the floor, merging it with a matching stack on the same tile
+fn place_on_floor(item: Object, objects: &mut Vec<Object>) {
+	match objects.iter().position(|other| other.pos() == item.pos() && other.stacks_with(&item)) {
+		Some(stack) => merge_stack(&mut objects[stack], item),
+		None => objects.push(item),
+	}
+}
+
+fn merge_stack(stack: &mut Object, item: Object) {
+	let count = item.item.map_or(1, |item| item.count);
+	stack.item.as_mut().unwrap().count += count;
+}
+
+// Take some of a stack out of an inventory, or the whole item if that's all of it
+fn split_stack(inventory_id: usize, amount: i32, inventory: &mut Vec<Object>) -> Object {
+	let count = inventory[inventory_id].item.as_ref().map_or(1, |item| item.count);
+	if amount >= count {
+		return inventory.remove(inventory_id);
+	}
+	inventory[inventory_id].item.as_mut().unwrap().count -= amount;
+	let mut split = inventory[inventory_id].clone();
+	split.item.as_mut().unwrap().count = amount;
+	split
 }
 
 #[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
@@ -340,31 +1754,76 @@ enum DeathCallback {
 }
 
 impl DeathCallback {
-	fn callback(self, object: &mut Object, game: &mut Game) {
+	// the killer's faction, if anyone did the killing
+	fn callback(self, object: &mut Object, killer: Option<Faction>, game: &mut Game) {
 		use DeathCallback::*;
-		let callback: fn(&mut Object, &mut Game) = match self {
+		let callback: fn(&mut Object, Option<Faction>, &mut Game) = match self {
 			Player => player_death,
 			Monster => monster_death,
 		};
-		callback(object, game);
+		callback(object, killer, game);
 	}
 }
 
-fn player_death(player: &mut Object, game: &mut Game) {
+fn player_death(player: &mut Object, _killer: Option<Faction>, game: &mut Game) {
 	game.log.add("You died!", colors::RED);
 	// transform player into a corpse char
 	player.char = '%';
 	player.color = colors::DARK_RED;
 }
 
-fn monster_death(monster: &mut Object, game: &mut Game) {
+fn monster_death(monster: &mut Object, killer: Option<Faction>, game: &mut Game) {
 	game.log.add(format!("{} was slain. {} xp", monster.name, monster.fighter.unwrap().xp), colors::AZURE);
+	// only the player's side gets the credit
+	if killer == Some(Faction::Player) {
+		game.quest_event(QuestEvent::Killed(&monster.name));
+		game.kills += 1;
+	}
+	if let Some(fate) = game.uniques.get_mut(&monster.name) {
+		*fate = UniqueFate::Slain;
+	}
+	// the corpse can be eaten, though it might not agree with you
+	let nutrition = monster.fighter.map_or(0, |f| f.max_hp) * CORPSE_NUTRITION;
+	let message = format!("You eat the remains of the {}.", monster.name);
 	monster.char = '%';
 	monster.color = colors::DARK_RED;
 	monster.blocks = false;
 	monster.fighter = None;
 	monster.ai = None;
 	monster.name = format!("Remains of {}", monster.name);
+	monster.item = Some(Item {
+		kind: CORPSE_KIND.into(),
+		targeting: Targeting::User,
+		effects: vec![Effect::Nourish { amount: nutrition, sick_chance: CORPSE_SICK_CHANCE }],
+		message: Some(message),
+		stackable: false,
+		count: 1,
+		plural: "corpses".into(),
+		charges: None,
+		class: None,
+		ammo: None,
+		price: 0,
+		unpaid: false,
+	});
+}
+
+fn gold_pile(x: i32, y: i32, amount: i32) -> Object {
+	let mut gold = Object::new(x, y, '$', colors::GOLD, GOLD_KIND, false);
+	gold.item = Some(Item {
+		kind: GOLD_KIND.into(),
+		targeting: Targeting::User,
+		effects: vec![],
+		message: None,
+		stackable: true,
+		count: amount,
+		plural: "gold pieces".into(),
+		charges: None,
+		class: None,
+		ammo: None,
+		price: 0,
+		unpaid: false,
+	});
+	gold
 }
 
 #[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
@@ -380,8 +1839,43 @@ struct Fighter {
 #[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
 struct Equipment {
 	slot: Slot,
+	#[serde(default)]
 	equipped: bool,
+	#[serde(default)]
+	two_handed: bool,
+	#[serde(default)]
 	power_bonus: i32,
+	#[serde(default)]
+	defense_bonus: i32,
+	#[serde(default)]
+	max_hp_bonus: i32,
+	#[serde(default)]
+	launcher: Option<Launcher>,
+}
+
+// bows and slings, and the ammunition they shoot
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+struct Launcher {
+	ammo: Ammo,
+	range: i32,
+	power: i32,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+enum Ammo {
+	Arrow,
+	Stone,
+}
+
+impl Equipment {
+	// all the slots taken up while this is worn, two-handed weapons need both hands
+	pub fn slots(&self) -> Vec<Slot> {
+		if self.two_handed {
+			vec![Slot::RightHand, Slot::LeftHand]
+		} else {
+			vec![self.slot]
+		}
+	}
 }
 
 #[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
@@ -389,6 +1883,23 @@ enum Slot {
 	LeftHand,
 	RightHand,
 	Head,
+	Body,
+	Hands,
+	Feet,
+	LeftRing,
+	RightRing,
+	Amulet,
+}
+
+impl Slot {
+	// rings can be worn on either hand
+	pub fn alternative(self) -> Option<Slot> {
+		match self {
+			Slot::LeftRing => Some(Slot::RightRing),
+			Slot::RightRing => Some(Slot::LeftRing),
+			_ => None,
+		}
+	}
 }
 
 impl std::fmt::Display for Slot {
@@ -397,6 +1908,12 @@ impl std::fmt::Display for Slot {
 			Slot::LeftHand => write!(f, "left hand"),
 			Slot::RightHand => write!(f, "right hand"),
 			Slot::Head => write!(f, "head"),
+			Slot::Body => write!(f, "body"),
+			Slot::Hands => write!(f, "hands"),
+			Slot::Feet => write!(f, "feet"),
+			Slot::LeftRing => write!(f, "left ring finger"),
+			Slot::RightRing => write!(f, "right ring finger"),
+			Slot::Amulet => write!(f, "neck"),
 		}
 	}
 }
@@ -405,9 +1922,38 @@ impl std::fmt::Display for Slot {
 enum Ai {
 	Basic,
 	Confused { previous_ai: Box<Ai>, num_turns: i32 },
+	// minds the shop in this room until robbed or attacked
+	Shopkeeper { room: Rect },
+}
+
+// who fights whom
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+enum Faction {
+	// the player and their allies
+	Player,
+	// leaves everyone alone until provoked
+	Neutral,
+	// only has it in for the player
+	Hostile,
+	Orcs,
+	Trolls,
+}
+
+impl Faction {
+	fn is_hostile_to(self, other: Faction) -> bool {
+		use Faction::*;
+		match (self, other) {
+			(Neutral, _) | (_, Neutral) => false,
+			(Player, Player) => false,
+			(Player, _) | (_, Player) => true,
+			// old rivals
+			(Orcs, Trolls) | (Trolls, Orcs) => true,
+			_ => false,
+		}
+	}
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Clone, Debug, Serialize, Deserialize)]
 struct Object {
 	x: i32,
 	y: i32,
@@ -422,17 +1968,31 @@ struct Object {
 	always_visible: bool,
 	level: i32,
 	equipment: Option<Equipment>,
+	inventory: Vec<Object>,
+	// only things that need to eat have this
+	nutrition: Option<i32>,
+	gold: i32,
+	faction: Faction,
+	// what it has to say when the player bumps into it
+	dialogue: Option<String>,
+	abilities: Vec<Ability>,
+	mana: i32,
+	max_mana: i32,
+	// turns the player has left stumbling about, monsters get a confused Ai instead
+	confused: i32,
+	// a shopkeeper stays one even after turning on the player
+	keeps_shop: bool,
 }
 
 impl Object {
 	pub fn new(x: i32, y: i32, char: char, color: Color, name: &str, blocks: bool) -> Self {
 		Object {
-			x: x,
-			y: y,
-			char: char,
-			color: color,
+			x,
+			y,
+			char,
+			color,
 			name: name.into(),
-			blocks: blocks,
+			blocks,
 			alive: false,
 			fighter: None,
 			ai: None,
@@ -440,17 +2000,27 @@ impl Object {
 			always_visible: false,
 			level: 1,
 			equipment: None,
+			inventory: vec![],
+			nutrition: None,
+			gold: 0,
+			faction: Faction::Neutral,
+			dialogue: None,
+			abilities: vec![],
+			mana: 0,
+			max_mana: 0,
+			confused: 0,
+			keeps_shop: false,
 		}
 	}
 
 	// set the color and then draw the character at its position
-	pub fn draw(&self, con: &mut Console) {
+	pub fn draw(&self, con: &mut dyn Console) {
 		con.set_default_foreground(self.color);
 		con.put_char(self.x, self.y, self.char, BackgroundFlag::None);
 	}
 
 	// Erase the character
-	pub fn clear(&self, con: &mut Console) {
+	pub fn clear(&self, con: &mut dyn Console) {
 		con.put_char(self.x, self.y, ' ', BackgroundFlag::None);
 	}
 
@@ -458,6 +2028,49 @@ impl Object {
 		(self.x, self.y)
 	}
 
+	// name as far as the player knows it, including the size of the stack,
+	// like "3 healing potions" or "murky potion called ick"
+	pub fn display_name(&self, game: &Game) -> String {
+		let item = match self.item {
+			Some(ref item) => item,
+			None => return self.name.clone(),
+		};
+		let (name, plural) = match game.appearances.get(&item.kind) {
+			Some(appearance) if !game.is_identified(&item.kind) => {
+				match game.called.get(&item.kind) {
+					Some(called) => (format!("{} called {}", appearance.name, called),
+					                 format!("{} called {}", appearance.plural, called)),
+					None => (appearance.name.clone(), appearance.plural.clone()),
+				}
+			}
+			_ => (self.name.clone(), item.plural.clone()),
+		};
+		let name = if item.count > 1 {
+			format!("{} {}", item.count, plural)
+		} else {
+			name
+		};
+		// charges only show once the player knows what it is
+		let name = match item.charges {
+			Some(charges) if game.is_identified(&item.kind) => format!("{} ({} charges)", name, charges),
+			_ => name,
+		};
+		if item.unpaid {
+			format!("{} (unpaid, {} gold)", name, item.price * item.count)
+		} else {
+			name
+		}
+	}
+
+	pub fn stacks_with(&self, other: &Object) -> bool {
+		match (self.item.as_ref(), other.item.as_ref()) {
+			(Some(item), Some(other_item)) => {
+				item.stackable && item.kind == other_item.kind && item.unpaid == other_item.unpaid
+			}
+			_ => false,
+		}
+	}
+
 	pub fn set_pos(&mut self, x: i32, y: i32) {
 		self.x = x;
 		self.y = y;
@@ -473,7 +2086,11 @@ impl Object {
 		(((x - self.x).pow(2) + (y - self.y).pow(2)) as f32).sqrt()
 	}
 
-	pub fn take_damage(&mut self, damage: i32, game: &mut Game) -> Option<i32> {
+	pub fn take_damage(&mut self, damage: i32, killer: Option<Faction>, game: &mut Game) -> Option<i32> {
+		if damage > 0 {
+			self.provoke(game);
+		}
+
 		// apply damage if possible
 		if let Some(fighter) = self.fighter.as_mut() {
 			if damage > 0 {
@@ -485,7 +2102,7 @@ impl Object {
 		if let Some(fighter) = self.fighter {
 			if fighter.hp <= 0 {
 				self.alive = false;
-				fighter.on_death.callback(self, game);
+				fighter.on_death.callback(self, killer, game);
 				return Some(fighter.xp);
 			}
 		}
@@ -495,10 +2112,10 @@ impl Object {
 
 	pub fn attack(&mut self, target: &mut Object, game: &mut Game) {
 		// simple formula for attack damage
-		let damage = self.power(game) - target.fighter.map_or(0, |f| f.defense);
+		let damage = self.power() - target.defense();
 		if damage > 0 {
 			game.log.add(format!("{} attacks {} for {} hp.", self.name, target.name, damage), colors::WHITE);
-			if let Some(xp) = target.take_damage(damage, game) {
+			if let Some(xp) = target.take_damage(damage, Some(self.faction), game) {
 				self.fighter.as_mut().unwrap().xp += xp;
 			}
 		} else {
@@ -507,10 +2124,11 @@ impl Object {
 	}
 
 	pub fn heal(&mut self, amount: i32) {
+		let max_hp = self.max_hp();
 		if let Some(ref mut fighter) = self.fighter {
 			fighter.hp += amount;
-			if fighter.hp > fighter.max_hp {
-				fighter.hp = fighter.max_hp;
+			if fighter.hp > max_hp {
+				fighter.hp = max_hp;
 			}
 		}
 	}
@@ -524,7 +2142,11 @@ impl Object {
 		if let Some(ref mut equipment) = self.equipment {
 			if !equipment.equipped {
 				equipment.equipped = true;
-				log.add(format!("Equipped {} on {}.", self.name, equipment.slot), colors::LIGHT_GREEN);
+				if equipment.two_handed {
+					log.add(format!("Equipped {} in both hands.", self.name), colors::LIGHT_GREEN);
+				} else {
+					log.add(format!("Equipped {} on {}.", self.name, equipment.slot), colors::LIGHT_GREEN);
+				}
 			}
 		} else {
 			log.add(format!("Can't equip {:?} because not an equipment.", self), colors::RED);
@@ -547,27 +2169,139 @@ impl Object {
 		}		
 	}
 
-	pub fn power(&self, game: &Game) -> i32 {
+	pub fn power(&self) -> i32 {
 		let base_power = self.fighter.map_or(0, |f| f.base_power);
-		let bonus = self.get_all_equipped(game).iter().fold(0, |sum, e| sum + e.power_bonus);
-		base_power + bonus
+		let bonus = self.get_all_equipped().iter().fold(0, |sum, e| sum + e.power_bonus);
+		base_power + bonus - self.hunger().power_penalty()
 	}
 
-	pub fn get_all_equipped(&self, game: &Game) -> Vec<Equipment> {
-		if self.name == "Player" {
-			game.inventory
-				.iter()
-				.filter(|item| {
-					item.equipment.map_or(false, |e| e.equipped)
-				})
-				.map(|item| item.equipment.unwrap())
-				.collect()
+	pub fn defense(&self) -> i32 {
+		let base_defense = self.fighter.map_or(0, |f| f.defense);
+		let bonus = self.get_all_equipped().iter().fold(0, |sum, e| sum + e.defense_bonus);
+		base_defense + bonus - self.hunger().defense_penalty()
+	}
+
+	// neutrals turn on whoever troubles them
+	pub fn provoke(&mut self, game: &mut Game) {
+		if self.faction != Faction::Neutral {
+			return;
+		}
+		game.log.add(format!("The {} is furious!", self.name), colors::RED);
+		self.turn_hostile();
+	}
+
+	pub fn turn_hostile(&mut self) {
+		self.faction = Faction::Hostile;
+		// a shopkeeper stops minding the shop to come after you
+		if self.is_shopkeeper() {
+			self.ai = Some(Ai::Basic);
+		}
+	}
+
+	pub fn is_shopkeeper(&self) -> bool {
+		matches!(self.ai, Some(Ai::Shopkeeper { .. }))
+	}
+
+	pub fn hunger(&self) -> Hunger {
+		self.nutrition.map_or(Hunger::NotHungry, Hunger::from_nutrition)
+	}
+
+	pub fn max_hp(&self) -> i32 {
+		let base_max_hp = self.fighter.map_or(0, |f| f.max_hp);
+		let bonus = self.get_all_equipped().iter().fold(0, |sum, e| sum + e.max_hp_bonus);
+		base_max_hp + bonus
+	}
+
+	pub fn get_all_equipped(&self) -> Vec<Equipment> {
+		self.inventory
+			.iter()
+			.filter(|item| {
+				item.equipment.is_some_and(|e| e.equipped)
+			})
+			.map(|item| item.equipment.unwrap())
+			.collect()
+	}
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Hunger {
+	NotHungry,
+	Hungry,
+	Weak,
+	Fainting,
+}
+
+impl Hunger {
+	fn from_nutrition(nutrition: i32) -> Self {
+		if nutrition > HUNGRY_AT {
+			Hunger::NotHungry
+		} else if nutrition > WEAK_AT {
+			Hunger::Hungry
+		} else if nutrition > FAINTING_AT {
+			Hunger::Weak
 		} else {
-			vec![]
+			Hunger::Fainting
+		}
+	}
+
+	fn power_penalty(self) -> i32 {
+		match self {
+			Hunger::NotHungry => 0,
+			Hunger::Hungry => 1,
+			Hunger::Weak => 2,
+			Hunger::Fainting => 3,
+		}
+	}
+
+	fn defense_penalty(self) -> i32 {
+		match self {
+			Hunger::NotHungry | Hunger::Hungry => 0,
+			Hunger::Weak => 1,
+			Hunger::Fainting => 2,
+		}
+	}
+
+	// shown in the panel, and what the player is told on getting there
+	fn status(self) -> Option<(&'static str, &'static str, Color)> {
+		match self {
+			Hunger::NotHungry => None,
+			Hunger::Hungry => Some(("Hungry", "You are beginning to feel hungry.", colors::YELLOW)),
+			Hunger::Weak => Some(("Weak", "You feel weak from hunger.", colors::ORANGE)),
+			Hunger::Fainting => Some(("Fainting", "You are fainting from lack of food!", colors::RED)),
 		}
 	}
 }
 
+// Use up one turn of food. Returns how many extra turns the player loses to fainting.
+fn digest(player: &mut Object, game: &mut Game) -> i32 {
+	let before = player.hunger();
+	let nutrition = match player.nutrition.as_mut() {
+		Some(nutrition) => {
+			*nutrition -= 1;
+			*nutrition
+		}
+		None => return 0,
+	};
+	let after = player.hunger();
+	if after != before {
+		if let Some((_, message, color)) = after.status() {
+			game.log.add(message, color);
+		}
+	}
+
+	if nutrition <= STARVED_AT {
+		game.log.add("You starve to death.", colors::RED);
+		let hp = player.fighter.map_or(0, |f| f.hp);
+		player.take_damage(hp, None, game);
+		return 0;
+	}
+	if after == Hunger::Fainting && rand::thread_rng().gen_range(0, 100) < FAINT_CHANCE {
+		game.log.add("You faint from lack of food.", colors::RED);
+		return FAINT_TURNS;
+	}
+	0
+}
+
 #[derive(Clone, Copy, Debug, Serialize, Deserialize)]
 struct Tile {
 	blocked: bool,
@@ -585,6 +2319,7 @@ impl Tile {
 	}
 }
 
+#[derive(Debug, Deserialize)]
 struct Transition {
 	level: u32,
 	value: u32,
@@ -599,7 +2334,7 @@ fn from_dungeon_level(table: &[Transition], level: u32) -> u32 {
 		.map_or(0, |transition| transition.value)
 }
 
-fn make_map(objects: &mut Vec<Object>, level: u32) -> Map {
+fn make_map(objects: &mut Vec<Object>, level: u32, data: &Data) -> Map {
 	let mut map = vec![vec![Tile::wall(); MAP_HEIGHT as usize]; MAP_WIDTH as usize];
 	let mut rooms = vec![];
 	assert_eq!(&objects[PLAYER] as *const _, &objects[0] as *const _);
@@ -617,13 +2352,16 @@ fn make_map(objects: &mut Vec<Object>, level: u32) -> Map {
 
 		if !failed {
 			create_room(new_room, &mut map);
-			place_objects(new_room, &map, objects, level);
 
 			let (new_x, new_y) = new_room.center();
 
 			if rooms.is_empty() {
-				// this is the first valid room generated, start player here
-				objects[PLAYER].set_pos(new_x, new_y)
+				// this is the first valid room generated, start player here,
+				// on the way back up
+				objects[PLAYER].set_pos(new_x, new_y);
+				let mut stairs = Object::new(new_x, new_y, '<', colors::WHITE, "stairs up", false);
+				stairs.always_visible = true;
+				objects.push(stairs);
 			} else {
 				// connect to previous room with a tunnel
 				let (prev_x, prev_y) = rooms[rooms.len() - 1].center();
@@ -643,18 +2381,43 @@ fn make_map(objects: &mut Vec<Object>, level: u32) -> Map {
 		}
 	}
 
-	// create stairs at center of the last room
-	let (last_room_x, last_room_y) = rooms[rooms.len() - 1].center();
-	let mut stairs = Object::new(last_room_x, last_room_y, '<', colors::WHITE, "stairs", false);
-	stairs.always_visible = true;
-	objects.push(stairs);
-	map
-}
-
-fn render_all(tcod: &mut Tcod, game: &mut Game, objects: &[Object], fov_recompute: bool) {
-	if fov_recompute {
-		let player = &objects[PLAYER];
-		tcod.fov.compute_fov(player.x, player.y, TORCH_RADIUS, FOV_LIGHT_WALLS, FOV_ALGO);
+	// sometimes one of the rooms between the first and the last is a shop
+	let shop = if rooms.len() > 2 && rand::thread_rng().gen_range(0, 100) < SHOP_CHANCE {
+		Some(rand::thread_rng().gen_range(1, rooms.len() - 1))
+	} else {
+		None
+	};
+	for (id, &room) in rooms.iter().enumerate() {
+		if shop == Some(id) {
+			make_shop(room, &map, objects, level, data);
+		} else {
+			place_objects(room, &map, objects, level, data);
+		}
+	}
+
+	let last_room = rooms[rooms.len() - 1];
+	let (last_room_x, last_room_y) = last_room.center();
+	if level >= FINAL_LEVEL {
+		// the bottom of the dungeon: the artifact, and what guards it
+		let artifact = data.items.iter().find(|item| item.name == ARTIFACT).expect("the artifact is checked on load");
+		objects.push(create_item_from_template(artifact, last_room_x, last_room_y));
+		let boss = data.monsters.iter().find(|monster| monster.name == FINAL_BOSS).expect("the boss is checked on load");
+		if let Some((x, y)) = free_spot_near(last_room_x, last_room_y, last_room, &map, objects) {
+			objects.push(create_monster(x, y, boss, level, data));
+		}
+	} else {
+		// create stairs at center of the last room
+		let mut stairs = Object::new(last_room_x, last_room_y, '>', colors::WHITE, "stairs down", false);
+		stairs.always_visible = true;
+		objects.push(stairs);
+	}
+	map
+}
+
+fn render_all(tcod: &mut Tcod, game: &mut Game, objects: &[Object], fov_recompute: bool) {
+	if fov_recompute {
+		let player = &objects[PLAYER];
+		tcod.fov.compute_fov(player.x, player.y, TORCH_RADIUS, FOV_LIGHT_WALLS, FOV_ALGO);
 
 		for y in 0..MAP_HEIGHT {
 			for x in 0..MAP_WIDTH {
@@ -693,7 +2456,7 @@ fn render_all(tcod: &mut Tcod, game: &mut Game, objects: &[Object], fov_recomput
 	}
 
 	// blit the contents of "con" to the root console and present it
-    blit(&mut tcod.con, (0, 0), (MAP_WIDTH, MAP_HEIGHT), &mut tcod.root, (0, 0), 1.0, 1.0);
+    blit(&tcod.con, (0, 0), (MAP_WIDTH, MAP_HEIGHT), &mut tcod.root, (0, 0), 1.0, 1.0);
 
 	// prepare to render GUI
 	tcod.panel.set_default_background(colors::BLACK);
@@ -701,33 +2464,49 @@ fn render_all(tcod: &mut Tcod, game: &mut Game, objects: &[Object], fov_recomput
 
 	// player stats
 	let hp = objects[PLAYER].fighter.map_or(0, |f| f.hp);
-	let max_hp = objects[PLAYER].fighter.map_or(0, |f| f.max_hp);
+	let max_hp = objects[PLAYER].max_hp();
 	render_bar(&mut tcod.panel, 1, 1, BAR_WIDTH, "HP", hp, max_hp, colors::LIGHT_RED, colors::DARKER_RED);
+	let player = &objects[PLAYER];
+	if player.max_mana > 0 {
+		render_bar(&mut tcod.panel, 1, 2, BAR_WIDTH, "Mana", player.mana, player.max_mana, colors::LIGHT_BLUE, colors::DARKER_BLUE);
+	}
 	tcod.panel.print_ex(1, 3, BackgroundFlag::None, TextAlignment::Left,
 						format!("Dungeon level: {}", game.dungeon_level));
+	if carries_artifact(&objects[PLAYER]) {
+		tcod.panel.set_default_foreground(colors::LIGHT_YELLOW);
+		tcod.panel.print_ex(1, 6, BackgroundFlag::None, TextAlignment::Left, "Escape!");
+		tcod.panel.set_default_foreground(colors::WHITE);
+	}
+	tcod.panel.print_ex(1, 5, BackgroundFlag::None, TextAlignment::Left,
+						format!("Gold: {}", objects[PLAYER].gold));
+	if let Some((status, _, color)) = objects[PLAYER].hunger().status() {
+		tcod.panel.set_default_foreground(color);
+		tcod.panel.print_ex(1, 4, BackgroundFlag::None, TextAlignment::Left, status);
+	}
 
 	// display names of objects under mouse
 	tcod.panel.set_default_foreground(colors::LIGHT_GREY);
 	tcod.panel.print_ex(1, 0, BackgroundFlag::None, TextAlignment::Left,
-		           get_names_under_mouse(tcod.mouse, objects, &mut tcod.fov));
+		           get_names_under_mouse(tcod.mouse, objects, &tcod.fov, game));
 
 	// print the game messages, one line at a time
 	let mut y = MSG_HEIGHT as i32;
-	for &(ref msg, color) in game.log.iter().rev() {
-		let msg_height = tcod.panel.get_height_rect(MSG_X, y, MSG_WIDTH, 0, msg);
+	for message in game.log.messages.iter().rev() {
+		let msg = message.text();
+		let msg_height = tcod.panel.get_height_rect(MSG_X, y, MSG_WIDTH, 0, &msg);
 		y -= msg_height;
 		if y < 0 {
 			break;
 		}
-		tcod.panel.set_default_foreground(color);
+		tcod.panel.set_default_foreground(message.color);
 		tcod.panel.print_rect(MSG_X, y, MSG_WIDTH, 0, msg);
 	}
 
 	// blit the contents of panel to root console
-	blit(&mut tcod.panel, (0, 0), (SCREEN_WIDTH, PANEL_HEIGHT), &mut tcod.root, (0, PANEL_Y), 1.0, 1.0);	
+	blit(&tcod.panel, (0, 0), (SCREEN_WIDTH, PANEL_HEIGHT), &mut tcod.root, (0, PANEL_Y), 1.0, 1.0);	
 }
 
-#[derive(Clone, Copy, Debug)]
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
 struct Rect {
 	x1: i32,
 	y1: i32,
@@ -747,6 +2526,11 @@ impl Rect {
 		(center_x, center_y)
 	}
 
+	// is the tile on the room's floor
+	pub fn contains(&self, x: i32, y: i32) -> bool {
+		x > self.x1 && x < self.x2 && y > self.y1 && y < self.y2
+	}
+
 	pub fn intersects_with(&self, other: &Rect) -> bool {
 		// returns true if this rect intersects with another one
 		(self.x1 <= other.x2) && (self.x2 >= other.x1) &&
@@ -774,7 +2558,7 @@ fn create_v_tunnel(y1: i32, y2: i32, x: i32, map: &mut Map) {
 	}
 }
 
-fn place_objects(room: Rect, map: &Map, objects: &mut Vec<Object>, level: u32) {
+fn place_objects(room: Rect, map: &Map, objects: &mut Vec<Object>, level: u32, data: &Data) {
 	let max_monsters = from_dungeon_level(&[
 		Transition {level: 1, value: 2},
 		Transition {level: 4, value: 3},
@@ -786,8 +2570,16 @@ fn place_objects(room: Rect, map: &Map, objects: &mut Vec<Object>, level: u32) {
 		let y = rand::thread_rng().gen_range(room.y1 +1, room.y2);
 
 		if !is_blocked(x, y, map, objects) {
-			let monster = create_monster(x, y, level);
-			objects.push(monster);
+			let template = choose_monster(level, data);
+			objects.push(create_monster(x, y, template, level, data));
+
+			// the rest of the pack gathers around the first one
+			let pack_size = template.pack.roll();
+			for _ in 1..pack_size {
+				if let Some((x, y)) = free_spot_near(x, y, room, map, objects) {
+					objects.push(create_monster(x, y, template, level, data));
+				}
+			}
 		}
 	}
 
@@ -802,11 +2594,53 @@ fn place_objects(room: Rect, map: &Map, objects: &mut Vec<Object>, level: u32) {
 		let y = rand::thread_rng().gen_range(room.y1 +1, room.y2);
 
 		if !is_blocked(x, y, map, objects) {
-			// create a healing potion
-			let mut item = create_item(x, y);
+			let item = create_item(x, y, level, data);
 			objects.push(item);
 		}		
 	}
+
+	// a little gold lying around, more the deeper you go
+	if rand::thread_rng().gen_range(0, 100) < GOLD_CHANCE {
+		let x = rand::thread_rng().gen_range(room.x1 + 1, room.x2);
+		let y = rand::thread_rng().gen_range(room.y1 + 1, room.y2);
+		if !is_blocked(x, y, map, objects) {
+			let amount = rand::thread_rng().gen_range(1, 10 * level as i32 + 11);
+			objects.push(gold_pile(x, y, amount));
+		}
+	}
+}
+
+// a shopkeeper in the middle of the room, with goods for sale on the floor around them
+fn make_shop(room: Rect, map: &Map, objects: &mut Vec<Object>, level: u32, data: &Data) {
+	let (x, y) = room.center();
+	let mut keeper = Object::new(x, y, '@', colors::YELLOW, "shopkeeper", true);
+	keeper.fighter = Some(Fighter {
+		max_hp: 150,
+		hp: 150,
+		defense: 5,
+		base_power: 15,
+		on_death: DeathCallback::Monster,
+		xp: 500,
+	});
+	keeper.ai = Some(Ai::Shopkeeper { room });
+	keeper.keeps_shop = true;
+	keeper.faction = Faction::Neutral;
+	keeper.gold = SHOPKEEPER_GOLD;
+	keeper.alive = true;
+	objects.push(keeper);
+
+	for x in (room.x1 + 1)..room.x2 {
+		for y in (room.y1 + 1)..room.y2 {
+			if is_blocked(x, y, map, objects) || rand::thread_rng().gen_range(0, 100) >= SHOP_STOCK_CHANCE {
+				continue;
+			}
+			if let Some(template) = choose_item(level, data, |item| item.price > 0) {
+				let mut item = create_item_from_template(template, x, y);
+				item.item.as_mut().unwrap().unpaid = true;
+				objects.push(item);
+			}
+		}
+	}
 }
 
 fn is_blocked(x: i32, y: i32, map: &Map, objects: &[Object]) -> bool {
@@ -828,14 +2662,36 @@ fn move_by(id: usize, dx: i32, dy: i32, map: &Map, objects: &mut [Object]) {
 	let new_x = x + dx;
 	let new_y = y + dy;
 
-	if !is_blocked(new_x, new_y, map, objects) {
+	if !is_blocked(new_x, new_y, map, objects) && !cuts_corner(x, y, dx, dy, map) {
 		// move by a given amount
 		objects[id].set_pos(new_x, new_y);
 	}
 }
 
-fn player_move_or_attack(dx: i32, dy: i32, objects: &mut [Object], game: &mut Game) {
+// Diagonal steps can't squeeze past the corner of a wall, for the player and
+// monsters alike, whether moving or attacking
+fn cuts_corner(x: i32, y: i32, dx: i32, dy: i32, map: &Map) -> bool {
+	dx != 0 && dy != 0 &&
+		(map[(x + dx) as usize][y as usize].blocked || map[x as usize][(y + dy) as usize].blocked)
+}
+
+// Returns false if the step couldn't be tried at all, which doesn't use up a turn
+fn player_move_or_attack(dx: i32, dy: i32, objects: &mut Vec<Object>, game: &mut Game, tcod: &mut Tcod) -> bool {
 	let (x, y) = objects[PLAYER].pos();
+	// a confused player stumbles off any which way
+	let (dx, dy) = if objects[PLAYER].confused > 0 {
+		*rand::thread_rng().choose(DIRECTIONS).unwrap()
+	} else {
+		(dx, dy)
+	};
+	if cuts_corner(x, y, dx, dy, &game.map) {
+		// stumbling into the wall still wastes the turn
+		if objects[PLAYER].confused > 0 {
+			return true;
+		}
+		game.log.add("You can't squeeze past the corner.", colors::WHITE);
+		return false;
+	}
 
 	let new_x = x + dx;
 	let new_y = y + dy; 
@@ -845,71 +2701,239 @@ fn player_move_or_attack(dx: i32, dy: i32, objects: &mut [Object], game: &mut Ga
 	});
 
 	match target_id {
+		Some(target_id) if objects[target_id].is_shopkeeper() => {
+			shop_menu(target_id, objects, game, tcod);
+		}
+		Some(target_id) if objects[target_id].dialogue.is_some() &&
+			!objects[PLAYER].faction.is_hostile_to(objects[target_id].faction) => {
+			talk(target_id, objects, game, tcod);
+		}
+		Some(target_id) if objects[target_id].faction == Faction::Player => {
+			// trade places with allies instead of hitting them
+			let ally_pos = objects[target_id].pos();
+			objects[target_id].set_pos(x, y);
+			objects[PLAYER].set_pos(ally_pos.0, ally_pos.1);
+			game.log.add(format!("You swap places with the {}.", objects[target_id].name), colors::WHITE);
+		}
+		Some(target_id) if objects[target_id].faction == Faction::Neutral => {
+			let question = format!("Really attack the {}?", objects[target_id].name);
+			if menu(&question, &["Yes", "No"], INVENTORY_WIDTH, &mut tcod.root) == Some(0) {
+				player_attack(target_id, objects, game);
+			}
+		}
 		Some(target_id) => {
-			let (player, target) = mut_two(PLAYER, target_id, objects);
-			player.attack(target, game);
+			player_attack(target_id, objects, game);
 		}
 		None => {
 			move_by(PLAYER, dx, dy, &game.map, objects);
 		}
 	}
+	true
+}
+
+fn player_attack(target_id: usize, objects: &mut [Object], game: &mut Game) {
+	let (player, target) = mut_two(PLAYER, target_id, objects);
+	player.attack(target, game);
+	if target.alive && rand::thread_rng().gen_range(0, 100) < game.double_strike_chance() {
+		game.log.add("You strike again!", colors::LIGHT_YELLOW);
+		player.attack(target, game);
+	}
 }
 
-fn create_player() -> Object {
+fn create_player(class: &ClassTemplate, data: &Data) -> Object {
 	let mut player = Object::new(0, 0, '@', colors::WHITE, "Player", true);
 	player.alive = true;	
 	player.fighter = Some(Fighter {
-		max_hp: 100,
-		hp: 100,
-		defense: 1,
-		base_power: 4,
+		max_hp: class.fighter.max_hp,
+		hp: class.fighter.max_hp,
+		defense: class.fighter.defense,
+		base_power: class.fighter.power,
 		on_death: DeathCallback::Player,
 		xp: 0,
 	});
+	player.nutrition = Some(START_NUTRITION);
+	player.faction = Faction::Player;
+	player.gold = class.gold;
+	player.max_mana = class.mana;
+	player.mana = class.mana;
+
+	// starting kit, with anything that can be worn already on
+	for name in &class.items {
+		let template = data.items.iter().find(|item| &item.name == name).expect("class items are checked on load");
+		let item = create_item_from_template(template, 0, 0);
+		let equipment = item.equipment;
+		let index = add_to_inventory(item, &mut player.inventory);
+		if let Some(equipment) = equipment {
+			if slot_is_free(equipment, &player.inventory) {
+				equip_item(index, &mut player.inventory, &mut Messages::new());
+			}
+		}
+	}
+	let max_hp = player.max_hp();
+	player.fighter.as_mut().unwrap().hp = max_hp;
 	player
 }
 
-fn create_monster(x: i32, y: i32, level: u32) -> Object {
-	use Monster::*;
-	let troll_chance = from_dungeon_level(&[
-		Transition {level: 3, value: 15},
-		Transition {level: 5, value: 30},
-		Transition {level: 7, value: 60},
-	], level);
-	// monster random table
-	let monster_changes = &mut [
-		Weighted {weight: 80, item: Orc},
-		Weighted {weight: troll_chance, item: Troll},
-	];
-	let monster_choice = WeightedChoice::new(monster_changes);
-	let mut monster = match monster_choice.ind_sample(&mut rand::thread_rng()) {
-		Orc => {
-			let mut orc = Object::new(x, y, 'O', colors::DESATURATED_GREEN, "Orc", true);
-			orc.fighter = Some(Fighter {
-				max_hp: 20,
-				hp: 20,
-				defense: 0,
-				base_power: 4,
-				on_death: DeathCallback::Monster,
-				xp: 35,
-			});
-			orc.ai = Some(Ai::Basic);
-			orc
-		}
-		Troll => {
-			let mut troll = Object::new(x, y, 'T', colors::DARKER_GREEN, "Troll", true);
-			troll.fighter = Some(Fighter {
-				max_hp: 30,
-				hp: 30,
-				defense: 2,
-				base_power: 8,
-				on_death: DeathCallback::Monster,
-				xp: 100,
-			});
-			troll.ai = Some(Ai::Basic);
-			troll
+// Pick one of the class's skills and use it. Returns true if it took a turn.
+fn use_skill(objects: &mut Vec<Object>, game: &mut Game, tcod: &mut Tcod) -> bool {
+	let mut skills = tcod.data.classes.iter()
+		.find(|class| class.name == game.class)
+		.map_or(vec![], |class| class.skills.clone());
+	for perk in &game.perks {
+		if let PerkBonus::Skill(ref skill) = perk.bonus {
+			skills.push(skill.clone());
+		}
+	}
+	if skills.is_empty() {
+		game.log.add(format!("A {} has no skills to use.", game.class), colors::WHITE);
+		return false;
+	}
+	let options = skills.iter().map(|skill| {
+		match game.cooldowns.get(&skill.name) {
+			Some(turns) => format!("{} ({} turns)", skill.name, turns),
+			None => format!("{} (ready)", skill.name),
+		}
+	}).collect::<Vec<_>>();
+	let skill = match menu("Use which skill?\n", &options, INVENTORY_WIDTH, &mut tcod.root) {
+		Some(index) => &skills[index],
+		None => return false,
+	};
+	if game.cooldowns.contains_key(&skill.name) {
+		game.log.add(format!("{} isn't ready yet.", skill.name), colors::WHITE);
+		return false;
+	}
+
+	if invoke(&skill.targeting, &skill.effects, &skill.message, objects, game, tcod) {
+		if skill.cooldown > 0 {
+			game.cooldowns.insert(skill.name.clone(), skill.cooldown);
 		}
+		true
+	} else {
+		false
+	}
+}
+
+// Pick a spell from the spellbook and cast it. Returns true if it took a turn.
+fn cast_spell(objects: &mut Vec<Object>, game: &mut Game, tcod: &mut Tcod) -> bool {
+	if game.spells.is_empty() {
+		game.log.add("You don't know any spells.", colors::WHITE);
+		return false;
+	}
+	let spells = game.spells.iter()
+		.map(|name| tcod.data.spells.iter().find(|spell| &spell.name == name).expect("known spells come from the spell data").clone())
+		.collect::<Vec<_>>();
+	let options = spells.iter().map(|spell| format!("{} ({} mana)", spell.name, spell.mana)).collect::<Vec<_>>();
+	let spell = match menu("Cast which spell?\n", &options, INVENTORY_WIDTH, &mut tcod.root) {
+		Some(index) => &spells[index],
+		None => return false,
+	};
+	if objects[PLAYER].mana < spell.mana {
+		game.log.add(format!("You don't have enough mana to cast {}.", spell.name), colors::WHITE);
+		return false;
+	}
+	if invoke(&spell.targeting, &spell.effects, &spell.message, objects, game, tcod) {
+		objects[PLAYER].mana -= spell.mana;
+		true
+	} else {
+		false
+	}
+}
+
+// Aim a skill or spell and let its effects loose, the same way items are used.
+// Returns false if it was cancelled or did nothing.
+fn invoke(targeting: &Targeting,
+          effects: &[Effect],
+          message: &str,
+          objects: &mut Vec<Object>,
+          game: &mut Game,
+          tcod: &mut Tcod)
+          -> bool
+{
+	let target = match choose_target(targeting, effects, objects, game, tcod) {
+		Some(target) => target,
+		None => return false,
 	};
+	game.log.add(target.message(message, objects), colors::LIGHT_BLUE);
+	apply_effects(effects, &target, PLAYER, objects, game, &tcod.data)
+}
+
+// the spells the player's class picks up on reaching this level
+fn learn_class_spells(level: i32, game: &mut Game, data: &Data) {
+	let class = data.classes.iter().find(|class| class.name == game.class).expect("the player's class comes from the class data");
+	for grant in class.spells.iter().filter(|grant| grant.level == level) {
+		if game.spells.len() >= MAX_SPELLS {
+			game.log.add(format!("You can't keep {} in your head along with all your other spells.", grant.spell),
+			             colors::WHITE);
+		} else if !game.spells.contains(&grant.spell) {
+			game.spells.push(grant.spell.clone());
+			game.log.add(format!("You have learned to cast {}.", grant.spell), colors::LIGHT_CYAN);
+		}
+	}
+}
+
+// return a random unblocked tile inside the room within two tiles of (x, y)
+fn free_spot_near(x: i32, y: i32, room: Rect, map: &Map, objects: &[Object]) -> Option<(i32, i32)> {
+	let mut spots = vec![];
+	for spot_x in cmp::max(x - 2, room.x1 + 1)..cmp::min(x + 3, room.x2) {
+		for spot_y in cmp::max(y - 2, room.y1 + 1)..cmp::min(y + 3, room.y2) {
+			if !is_blocked(spot_x, spot_y, map, objects) {
+				spots.push((spot_x, spot_y));
+			}
+		}
+	}
+	rand::thread_rng().choose(&spots).cloned()
+}
+
+// Pick a random index, weighted by each table's value at this level. None
+// when nothing in the tables can appear on the level.
+fn choose_by_level(tables: &[&[Transition]], level: u32) -> Option<usize> {
+	let chances = &mut tables.iter()
+		.enumerate()
+		.map(|(index, table)| Weighted {weight: from_dungeon_level(table, level), item: index})
+		.collect::<Vec<_>>();
+	if chances.iter().all(|chance| chance.weight == 0) {
+		return None;
+	}
+	let choice = WeightedChoice::new(chances);
+	Some(choice.ind_sample(&mut rand::thread_rng()))
+}
+
+fn choose_monster(level: u32, data: &Data) -> &MonsterTemplate {
+	let spawn_tables = data.monsters.iter().map(|template| &template.spawn[..]).collect::<Vec<_>>();
+	let index = choose_by_level(&spawn_tables, level).expect("monster spawn tables are validated on load");
+	&data.monsters[index]
+}
+
+fn create_monster(x: i32, y: i32, template: &MonsterTemplate, level: u32, data: &Data) -> Object {
+	let mut monster = Object::new(x, y, template.char, template.color, &template.name, true);
+	monster.fighter = Some(Fighter {
+		max_hp: template.fighter.max_hp,
+		hp: template.fighter.max_hp,
+		defense: template.fighter.defense,
+		base_power: template.fighter.power,
+		on_death: DeathCallback::Monster,
+		xp: template.xp,
+	});
+	monster.ai = Some(template.ai.clone());
+	monster.gold = template.gold.as_ref().map_or(0, |gold| gold.roll());
+	monster.faction = template.faction;
+	monster.dialogue = template.dialogue.clone();
+	monster.abilities = template.abilities.clone();
+	for name in &template.loot {
+		let item = data.items.iter().find(|item| &item.name == name).expect("loot is checked on load");
+		monster.inventory.push(create_item_from_template(item, x, y));
+	}
+	if rand::thread_rng().gen_range(0, 100) < template.weapon_chance {
+		let weapon = choose_item(level, data, |item| {
+			// monsters only fight hand to hand
+			item.equipment.is_some_and(|e| e.slot == Slot::RightHand && e.launcher.is_none())
+		});
+		if let Some(weapon) = weapon {
+			let mut weapon = create_item_from_template(weapon, x, y);
+			weapon.equipment.as_mut().unwrap().equipped = true;
+			monster.inventory.push(weapon);
+		}
+	}
 	monster.alive = true;
 	monster
 }
@@ -924,41 +2948,165 @@ fn move_towards(id: usize, target_x: i32, target_y: i32, map: &Map, objects: &mu
 	// convert to integer so the movement is restricted to map grid
 	let dx = (dx as f32 / distance).round() as i32;
 	let dy = (dy as f32 / distance).round() as i32;
-	move_by(id, dx, dy, map, objects);
+	let (x, y) = objects[id].pos();
+	if cuts_corner(x, y, dx, dy, map) {
+		// go around the corner instead
+		if map[(x + dx) as usize][y as usize].blocked {
+			move_by(id, 0, dy, map, objects);
+		} else {
+			move_by(id, dx, 0, map, objects);
+		}
+	} else {
+		move_by(id, dx, dy, map, objects);
+	}
 }
 
-fn ai_take_turn(monster_id: usize, objects: &mut [Object], fov_map: &FovMap, game: &mut Game) {
+// Returns the monster's index afterwards, since picking up an item moves it
+fn ai_take_turn(monster_id: usize, objects: &mut Vec<Object>, fov_map: &FovMap, game: &mut Game, data: &Data) -> usize {
 	use Ai::*;
 
+	let mut monster_id = monster_id;
+	// what a monster does out of the player's sight goes unreported
+	let (x, y) = objects[monster_id].pos();
+	let hidden_log = if fov_map.is_in_fov(x, y) {
+		None
+	} else {
+		Some(mem::replace(&mut game.log, Messages::new()))
+	};
 	if let Some(ai) = objects[monster_id].ai.take() {
 		let new_ai = match ai {
-			Basic => ai_basic(monster_id, game, objects, fov_map),
+			Basic => {
+				// grab anything lying underfoot instead of moving
+				let monster = &objects[monster_id];
+				let item_id = objects.iter().position(|object| {
+					object.pos() == monster.pos() && worth_picking_up(object)
+				});
+				match item_id {
+					Some(item_id) if fov_map.is_in_fov(monster.x, monster.y) && monster.inventory.len() < 26 => {
+						monster_id = pick_item_up(item_id, monster_id, objects, game);
+						Basic
+					}
+					_ => ai_basic(monster_id, game, objects, data),
+				}
+			}
 			Confused{previous_ai, num_turns} => ai_confused(
-				monster_id, game, objects, previous_ai, num_turns)
+				monster_id, game, objects, previous_ai, num_turns),
+			Shopkeeper { room } => Shopkeeper { room },
 		};
 		objects[monster_id].ai = Some(new_ai)
 	}
+	if let Some(log) = hidden_log {
+		game.log = log;
+	}
+	monster_id
 }
 
-fn ai_basic(monster_id: usize, game: &mut Game, objects: &mut [Object], fov_map: &FovMap) -> Ai {
-	// a basic monster takes its turn. If you can see it, it can see you
+fn ai_basic(monster_id: usize, game: &mut Game, objects: &mut Vec<Object>, data: &Data) -> Ai {
+	// a basic monster takes its turn. It goes for the nearest enemy it can see,
+	// and allies keep up with the player wherever they are
 	let (monster_x, monster_y) = objects[monster_id].pos();
-	let (player_x, player_y) = objects[PLAYER].pos();
-
-	if fov_map.is_in_fov(monster_x, monster_y) {
-		if objects[monster_id].distance_to(&objects[PLAYER]) >= 2.0 {
-			// move towards player if far away
+	let ally = objects[monster_id].faction == Faction::Player;
+
+	match closest_enemy(monster_id, objects, &game.map) {
+		Some(enemy_id) if use_ability(monster_id, enemy_id, objects, game, data) => {}
+		Some(enemy_id) => {
+			let (enemy_x, enemy_y) = objects[enemy_id].pos();
+			let around_corner = cuts_corner(monster_x, monster_y, enemy_x - monster_x, enemy_y - monster_y, &game.map);
+			if objects[monster_id].distance_to(&objects[enemy_id]) >= 2.0 || around_corner {
+				// move towards the enemy if far away
+				let (enemy_x, enemy_y) = objects[enemy_id].pos();
+				move_towards(monster_id, enemy_x, enemy_y, &game.map, objects);
+			} else {
+				// close enough to attack
+				let (monster, enemy) = mut_two(monster_id, enemy_id, objects);
+				monster.attack(enemy, game);
+			}
+		}
+		None if ally && objects[monster_id].distance_to(&objects[PLAYER]) >= 3.0 => {
+			let (player_x, player_y) = objects[PLAYER].pos();
 			move_towards(monster_id, player_x, player_y, &game.map, objects);
-		} else if objects[PLAYER].fighter.map_or(false, |f| f.hp > 0) {
-			// close enough to attack if player is still alive
-			let (monster, player) = mut_two(monster_id, PLAYER, objects);
-			monster.attack(player, game);
 		}
+		None => {}
 	}
 
 	Ai::Basic
 }
 
+// Give each of the monster's abilities a chance to be used against the enemy.
+// Returns true if one was, which takes up the monster's turn.
+fn use_ability(monster_id: usize, enemy_id: usize, objects: &mut Vec<Object>, game: &mut Game, data: &Data) -> bool {
+	let abilities = objects[monster_id].abilities.clone();
+	let distance = objects[monster_id].distance_to(&objects[enemy_id]);
+	let (enemy_x, enemy_y) = objects[enemy_id].pos();
+	for ability in &abilities {
+		if rand::thread_rng().gen_range(0, 100) >= ability.chance {
+			continue;
+		}
+		let target = match ability.targeting {
+			// abilities used on itself are saved for when it's hurt
+			Targeting::User if objects[monster_id].fighter.map_or(0, |f| f.hp) < objects[monster_id].max_hp() => {
+				Target::object(monster_id, objects)
+			}
+			Targeting::ClosestMonster { range } if distance <= range as f32 => Target::object(enemy_id, objects),
+			Targeting::Monster { range } if range.is_none_or(|range| distance <= range) => {
+				Target::object(enemy_id, objects)
+			}
+			Targeting::Tile { range } if range.is_none_or(|range| distance <= range) => {
+				Target::tile(enemy_x, enemy_y, objects)
+			}
+			_ => continue,
+		};
+		game.log.add(target.message(&ability.message, objects), colors::LIGHT_RED);
+		apply_effects(&ability.effects, &target, monster_id, objects, game, data);
+		return true;
+	}
+	false
+}
+
+// the nearest living thing in sight that this one wants to fight
+// whether anything blocks the view along a straight line between two tiles
+fn in_sight(from: (i32, i32), to: (i32, i32), map: &Map) -> bool {
+	let (dx, dy) = ((to.0 - from.0).abs(), -(to.1 - from.1).abs());
+	let (step_x, step_y) = ((to.0 - from.0).signum(), (to.1 - from.1).signum());
+	let mut error = dx + dy;
+	let (mut x, mut y) = from;
+	loop {
+		if (x, y) == to {
+			return true;
+		}
+		if (x, y) != from && map[x as usize][y as usize].block_sight {
+			return false;
+		}
+		let doubled = 2 * error;
+		if doubled >= dy {
+			error += dy;
+			x += step_x;
+		}
+		if doubled <= dx {
+			error += dx;
+			y += step_y;
+		}
+	}
+}
+
+fn closest_enemy(id: usize, objects: &[Object], map: &Map) -> Option<usize> {
+	let me = &objects[id];
+	let mut closest = None;
+	let mut closest_dist = f32::MAX;
+	for (other_id, other) in objects.iter().enumerate() {
+		if other_id != id && other.alive && other.fighter.is_some() &&
+			me.faction.is_hostile_to(other.faction)
+		{
+			let dist = me.distance_to(other);
+			if dist < closest_dist && dist <= MONSTER_SIGHT && in_sight(me.pos(), other.pos(), map) {
+				closest = Some(other_id);
+				closest_dist = dist;
+			}
+		}
+	}
+	closest
+}
+
 fn ai_confused(monster_id: usize, game: &mut Game, objects: &mut [Object], previous_ai: Box<Ai>, num_turns: i32) -> Ai {
 	if num_turns >= 0 { // still confused
 		move_by(monster_id,
@@ -966,7 +3114,7 @@ fn ai_confused(monster_id: usize, game: &mut Game, objects: &mut [Object], previ
 			    rand::thread_rng().gen_range(-1, 2),
 			    &game.map,
 			    objects);
-		Ai::Confused { previous_ai: previous_ai, num_turns: num_turns - 1 }
+		Ai::Confused { previous_ai, num_turns: num_turns - 1 }
 	} else {
 		game.log.add(format!("The {} is no longer confused!", objects[monster_id].name), colors::RED);
 		*previous_ai
@@ -1011,22 +3159,48 @@ fn render_bar(panel: &mut Offscreen,
 	// bar text
 	panel.set_default_foreground(colors::WHITE);
 	panel.print_ex(x + total_width / 2, y, BackgroundFlag::None, TextAlignment::Center,
-		           &format!("{}: {}/{}", name, value, maximum));
+		           format!("{}: {}/{}", name, value, maximum));
 }
 
-fn get_names_under_mouse(mouse: Mouse, objects: &[Object], fov_map: &FovMap) -> String {
-	let (x, y) = (mouse.cx as i32, mouse.cy as i32);
+fn get_names_under_mouse(mouse: Mouse, objects: &[Object], fov_map: &FovMap, game: &Game) -> String {
+	names_at(mouse.cx as i32, mouse.cy as i32, objects, fov_map, game)
+}
 
-	// create a list with the names of all objects at the mouse's coords and in FOV
+fn names_at(x: i32, y: i32, objects: &[Object], fov_map: &FovMap, game: &Game) -> String {
+	// create a list with the names of all objects at the coords and in FOV
 	let names = objects
 	  .iter()
 	  .filter(|obj| { obj.pos() == (x, y) && fov_map.is_in_fov(obj.x, obj.y) })
-	  .map(|obj| obj.name.clone())
+	  .map(|obj| obj.display_name(game))
 	  .collect::<Vec<_>>();
 
 	 names.join(", ")
 }
 
+// what the player can tell about a tile, for looking around and aiming
+fn describe_tile(x: i32, y: i32, objects: &[Object], fov_map: &FovMap, game: &Game) -> String {
+	let tile = &game.map[x as usize][y as usize];
+	if !tile.explored {
+		return "Unexplored".into();
+	}
+	let names = names_at(x, y, objects, fov_map, game);
+	if !names.is_empty() {
+		return names;
+	}
+	// stairs are remembered once seen
+	let remembered = objects.iter()
+		.filter(|obj| obj.pos() == (x, y) && obj.always_visible)
+		.map(|obj| obj.name.clone())
+		.collect::<Vec<_>>();
+	if !remembered.is_empty() {
+		remembered.join(", ")
+	} else if tile.blocked {
+		"Wall".into()
+	} else {
+		"Floor".into()
+	}
+}
+
 fn menu<T: AsRef<str>>(header: &str, options: &[T], width: i32, root: &mut Root) -> Option<usize> {
 	// Allow only 26 max options for now, 1 for each character in alphabet
 	assert!(options.len() <= 26, "Cannot have a menu with more than 26 options.");
@@ -1051,7 +3225,7 @@ fn menu<T: AsRef<str>>(header: &str, options: &[T], width: i32, root: &mut Root)
 	// blit
 	let x = SCREEN_WIDTH / 2 - width / 2;
 	let y = SCREEN_HEIGHT / 2 - height / 2;
-	blit(&mut window, (0, 0), (width, height), root, (x, y), 1.0, 0.7);
+	blit(&window, (0, 0), (width, height), root, (x, y), 1.0, 0.7);
 
 	// present root console and wait for key-press
 	root.flush();
@@ -1069,17 +3243,20 @@ fn menu<T: AsRef<str>>(header: &str, options: &[T], width: i32, root: &mut Root)
 	}
 }
 
-fn inventory_menu(inventory: &[Object], header: &str, root: &mut Root) -> Option<usize> {
+fn inventory_menu(inventory: &[Object], game: &Game, header: &str, root: &mut Root) -> Option<usize> {
 	// how a menu with each item of the inventory as an option
-	let options = if inventory.len() == 0 {
+	let options = if inventory.is_empty() {
 		vec!["Inventory is empty.".into()]
 	} else {
 		inventory.iter().map(|item| {
 			match item.equipment {
+				Some(equipment) if equipment.equipped && equipment.two_handed => {
+					format!("{} (in both hands)", item.display_name(game))
+				}
 				Some(equipment) if equipment.equipped => {
-					format!("{} (on {})", item.name, equipment.slot)
+					format!("{} (on {})", item.display_name(game), equipment.slot)
 				}
-				_ => item.name.clone()
+				_ => item.display_name(game)
 			}
 		}).collect()
 	};
@@ -1087,7 +3264,7 @@ fn inventory_menu(inventory: &[Object], header: &str, root: &mut Root) -> Option
 	let inventory_index = menu(header, &options, INVENTORY_WIDTH, root);
 
 	// if an item was chose, return it
-	if inventory.len() > 0 {
+	if !inventory.is_empty() {
 		inventory_index
 	} else {
 		None
@@ -1100,135 +3277,338 @@ enum UseResult {
 	UsedAndKept,
 }
 
-fn use_item(inventory_id: usize, objects: &mut[Object], game: &mut Game, tcod: &mut Tcod) {
-	use Item::*;
+fn use_item(inventory_id: usize, objects: &mut Vec<Object>, game: &mut Game, tcod: &mut Tcod) {
+	let item = objects[PLAYER].inventory[inventory_id].item.clone();
+	let is_equipment = objects[PLAYER].inventory[inventory_id].equipment.is_some();
 
-	if let Some(item) = game.inventory[inventory_id].item {
-		let on_use = match item {
-			Heal => cast_heal,
-			Lightning => cast_lightning,
-			Confuse => cast_confuse,
-			Fireball => cast_fireball,
-			Equipment => toggle_equipment,
-		};
-		match on_use(inventory_id, objects, game, tcod) {
-			UseResult::UsedUp => {
-				game.inventory.remove(inventory_id);
-			}
-			UseResult::UsedAndKept => {}
-			UseResult::Cancelled => {
-				game.log.add("Cancelled", colors::WHITE);
+	match item {
+		Some(Item { charges: Some(0), .. }) => {
+			// an empty wand is kept, it may be recharged later
+			game.log.add(
+				    format!("You wave the {}, but it only fizzles.", objects[PLAYER].inventory[inventory_id].display_name(game)),
+				    colors::WHITE);
+		}
+		Some(ref item) if is_equipment || !item.effects.is_empty() => {
+			let result = if is_equipment {
+				toggle_equipment(inventory_id, objects, game, tcod)
+			} else {
+				use_effects(item, objects, game, tcod)
+			};
+			match result {
+				UseResult::UsedUp if item.charges.is_some() => {
+					// wands and staves only use up a charge
+					if let Some(ref mut item) = objects[PLAYER].inventory[inventory_id].item {
+						item.charges = item.charges.map(|charges| charges - 1);
+					}
+				}
+				UseResult::UsedUp => {
+					// using one from a stack leaves the rest
+					split_stack(inventory_id, 1, &mut objects[PLAYER].inventory);
+				}
+				UseResult::UsedAndKept => {}
+				UseResult::Cancelled => {
+					game.log.add("Cancelled", colors::WHITE);
+				}
 			}
 		}
-	} else {
-		game.log.add(
-			    format!("The {} cannot be used.", game.inventory[inventory_id].name),
-			    colors::WHITE);
+		_ => {
+			game.log.add(
+				    format!("The {} cannot be used.", objects[PLAYER].inventory[inventory_id].display_name(game)),
+				    colors::WHITE);
+		}
 	}
 }
 
-fn toggle_equipment(inventory_id: usize, _objects: &mut [Object], game: &mut Game, _tcod: &mut Tcod) -> UseResult {
-	let equipment = match game.inventory[inventory_id].equipment {
+fn toggle_equipment(inventory_id: usize, objects: &mut [Object], game: &mut Game, _tcod: &mut Tcod) -> UseResult {
+	let player = &mut objects[PLAYER];
+	let equipment = match player.inventory[inventory_id].equipment {
 		Some(equipment) => equipment,
 		None => return UseResult::Cancelled,
 	};
 	if equipment.equipped {
-		game.inventory[inventory_id].dequip(&mut game.log);
+		player.inventory[inventory_id].dequip(&mut game.log);
 	} else {
-		if let Some(old_equipment) = get_equipped_in_slot(equipment.slot, &game.inventory) {
-			game.inventory[old_equipment].dequip(&mut game.log);
-		}
-		game.inventory[inventory_id].equip(&mut game.log);	
+		equip_item(inventory_id, &mut player.inventory, &mut game.log);
 	}
+	// losing a max HP bonus can leave the player above their new maximum
+	player.heal(0);
 	UseResult::UsedAndKept
 }
 
-fn cast_heal(_inventory_id: usize, objects: &mut [Object], game: &mut Game, _tcod: &mut Tcod) -> UseResult {
-	if let Some(fighter) = objects[PLAYER].fighter {
-		if fighter.hp == fighter.max_hp {
-			game.log.add("You are already at full health.", colors::RED);
-			return UseResult::Cancelled;
-		}
-		game.log.add("Your wounds start to feel better!", colors::LIGHT_VIOLET);
-		objects[PLAYER].heal(POTION_HEAL_AMOUNT);
-		return UseResult::UsedUp;
+// the tile something is aimed at and the creature standing there if any,
+// or an item in the user's inventory
+struct Target {
+	x: i32,
+	y: i32,
+	object: Option<usize>,
+	item: Option<usize>,
+}
+
+impl Target {
+	fn object(id: usize, objects: &[Object]) -> Self {
+		Target { x: objects[id].x, y: objects[id].y, object: Some(id), item: None }
+	}
+
+	fn tile(x: i32, y: i32, objects: &[Object]) -> Self {
+		let object = objects.iter().position(|object| {
+			object.pos() == (x, y) && object.fighter.is_some()
+		});
+		Target { x, y, object, item: None }
+	}
+
+	fn item(inventory_id: usize, objects: &[Object]) -> Self {
+		Target { x: objects[PLAYER].x, y: objects[PLAYER].y, object: None, item: Some(inventory_id) }
+	}
+
+	// fills in a use message: "{target}" is replaced by the target's name
+	fn message(&self, message: &str, objects: &[Object]) -> String {
+		let target_name = self.object.map_or("", |id| &objects[id].name);
+		message.replace("{target}", target_name)
 	}
-	UseResult::Cancelled
 }
 
-fn cast_lightning(_inventory_id: usize, objects: &mut [Object], game: &mut Game, tcod: &mut Tcod) -> UseResult {
-	// find closest enemy (inside a max range) and damage it
-	let monster_id = closest_monster(LIGHTNING_RANGE, objects, tcod);
-	if let Some(monster_id) = monster_id {
-		game.log.add(
-				format!("A lightning bolt strikes the {} with a loud thunder! \
-					     It deals {} damage.", objects[monster_id].name, LIGHTNING_DAMAGE),
-				colors::LIGHT_BLUE);
-		if let Some(xp) = objects[monster_id].take_damage(LIGHTNING_DAMAGE, game) {
-			objects[PLAYER].fighter.as_mut().unwrap().xp += xp;
+fn use_effects(item: &Item, objects: &mut Vec<Object>, game: &mut Game, tcod: &mut Tcod) -> UseResult {
+	let target = match choose_target(&item.targeting, &item.effects, objects, game, tcod) {
+		Some(target) => target,
+		None => return UseResult::Cancelled,
+	};
+	if let Some(ref message) = item.message {
+		game.log.add(target.message(message, objects), colors::LIGHT_BLUE);
+	}
+	if apply_effects(&item.effects, &target, PLAYER, objects, game, &tcod.data) {
+		// seeing what it does gives it away
+		if game.identify(&item.kind) {
+			game.log.add(format!("That must have been a {}.", item.kind), colors::LIGHT_CYAN);
 		}
 		UseResult::UsedUp
 	} else {
-		game.log.add("No enemy is close enough to strike.", colors::RED);
 		UseResult::Cancelled
 	}
 }
 
-fn cast_confuse(_inventory_id: usize, objects: &mut [Object], game: &mut Game, tcod: &mut Tcod) -> UseResult {
-	game.log.add("Left click to target an enemy.", colors::LIGHT_CYAN);
-	let monster_id =  target_monster(tcod, game, objects, Some(CONFUSE_RANGE as f32));
-	if let Some(monster_id) = monster_id {
-		let old_ai = objects[monster_id].ai.take().unwrap_or(Ai::Basic);
-		// replace the monster's AI with a "confused one"; 
-		// restore old AI after some turns
-		objects[monster_id].ai = Some(Ai::Confused {
-			previous_ai: Box::new(old_ai),
-			num_turns: CONFUSE_NUM_TURMS,
-		});
-		game.log.add(
-				format!("The {} is hit with a sudden jolt of confusion. It starts wandering aimlessly.", objects[monster_id].name),
-				colors::LIGHT_GREEN);
-		UseResult::UsedUp
-	} else {
-		game.log.add("No enemy is close enough to confuse.", colors::RED);
-		UseResult::Cancelled
+fn choose_target(targeting: &Targeting,
+                 effects: &[Effect],
+                 objects: &mut [Object],
+                 game: &mut Game,
+                 tcod: &mut Tcod)
+                 -> Option<Target>
+{
+	match *targeting {
+		Targeting::User => Some(Target::object(PLAYER, objects)),
+		Targeting::ClosestMonster { range } => {
+			// find closest enemy inside a max range
+			let monster_id = closest_monster(range, objects, tcod);
+			if monster_id.is_none() {
+				game.log.add("No enemy is close enough to strike.", colors::RED);
+			}
+			monster_id.map(|id| Target::object(id, objects))
+		}
+		Targeting::Monster { range } => {
+			game.log.add(TARGET_MONSTER_HELP, colors::LIGHT_CYAN);
+			target_monster(tcod, game, objects, range, area_of_effect(effects)).map(|id| Target::object(id, objects))
+		}
+		Targeting::Tile { range } => {
+			game.log.add(TARGET_TILE_HELP, colors::LIGHT_CYAN);
+			target_tile(tcod, game, objects, range, area_of_effect(effects)).map(|(x, y)| Target::tile(x, y, objects))
+		}
+		Targeting::InventoryItem => {
+			inventory_menu(
+				&objects[PLAYER].inventory,
+				game,
+				"Press the key next to an item to choose it, or any other to cancel.\n",
+				&mut tcod.root).map(|inventory_id| Target::item(inventory_id, objects))
+		}
 	}
 }
 
-fn cast_fireball(_inventory_id: usize, objects: &mut [Object], game: &mut Game, tcod: &mut Tcod) -> UseResult {
-	game.log.add(
-			"Left click a target tile for the fireball, or right click to cancel.",
-			colors::LIGHT_CYAN);
-	let (x, y) = match target_tile(tcod, game, objects, None) {
-		Some(tile_pos) => tile_pos,
-		None => return UseResult::Cancelled,
-	};
-	game.log.add(
-		    format!("The fireball explodes, burning everything within {} tiles!", FIREBALL_RADIUS),
-		    colors::ORANGE);
+// the widest radius among the effects, to show while aiming
+fn area_of_effect(effects: &[Effect]) -> i32 {
+	effects.iter()
+		.map(|effect| match *effect {
+			Effect::Damage { radius, .. } | Effect::Status { radius, .. } => radius,
+			_ => 0,
+		})
+		.max()
+		.unwrap_or(0)
+}
+
+// everything with a fighter caught by an effect of the given radius
+fn affected_objects(target: &Target, radius: i32, objects: &[Object]) -> Vec<usize> {
+	if radius == 0 {
+		return target.object.into_iter().collect();
+	}
+	objects.iter()
+		.enumerate()
+		.filter(|&(_, obj)| obj.distance(target.x, target.y) <= radius as f32 && obj.fighter.is_some())
+		.map(|(id, _)| id)
+		.collect()
+}
 
+// Apply each effect in turn, giving the caster xp for anything killed.
+// Returns false if none of them did anything.
+fn apply_effects(effects: &[Effect],
+                 target: &Target,
+                 caster_id: usize,
+                 objects: &mut Vec<Object>,
+                 game: &mut Game,
+                 data: &Data)
+                 -> bool
+{
+	let mut took_effect = false;
 	let mut xp_to_gain = 0;
-	for (id, obj) in objects.iter_mut().enumerate() {
-		if obj.distance(x, y) <= FIREBALL_RADIUS as f32 && obj.fighter.is_some() {
-			game.log.add(
-					format!("The {} gets blasted for {} hp.", obj.name, FIREBALL_DAMAGE),
-					colors::ORANGE);
-			if let Some(xp) = obj.take_damage(FIREBALL_DAMAGE, game) {
-				if id != PLAYER {
-					xp_to_gain += xp;
+
+	for effect in effects {
+		match *effect {
+			Effect::Heal { amount } => {
+				for id in affected_objects(target, 0, objects) {
+					let fighter = match objects[id].fighter {
+						Some(fighter) => fighter,
+						None => continue,
+					};
+					if fighter.hp == objects[id].max_hp() {
+						if id == PLAYER {
+							game.log.add("You are already at full health.", colors::RED);
+						}
+						continue;
+					}
+					if id == PLAYER {
+						game.log.add("Your wounds start to feel better!", colors::LIGHT_VIOLET);
+					} else {
+						game.log.add(format!("The {} looks healthier.", objects[id].name), colors::LIGHT_VIOLET);
+					}
+					objects[id].heal(amount);
+					took_effect = true;
+				}
+			}
+			Effect::Damage { amount, kind, radius } => {
+				// an explosion goes off whether or not anything is caught in it
+				took_effect |= radius > 0;
+				let caster_faction = objects[caster_id].faction;
+				for id in affected_objects(target, radius, objects) {
+					let amount = if id == PLAYER { game.resist(kind, amount) } else { amount };
+					game.log.add(
+							format!("The {} {} for {} hp.", objects[id].name, kind.verb(), amount),
+							colors::ORANGE);
+					if let Some(xp) = objects[id].take_damage(amount, Some(caster_faction), game) {
+						if id != caster_id {
+							xp_to_gain += xp;
+						}
+					}
+					took_effect = true;
+				}
+			}
+			Effect::Status { status: Status::Confused, turns, radius } => {
+				for id in affected_objects(target, radius, objects) {
+					objects[id].provoke(game);
+					if id == PLAYER {
+						objects[PLAYER].confused = turns;
+						game.log.add("You feel confused. Your feet won't go where you want them to!", colors::LIGHT_RED);
+						took_effect = true;
+					} else if let Some(old_ai) = objects[id].ai.take() {
+						// replace the monster's AI with a "confused one";
+						// restore old AI after some turns
+						objects[id].ai = Some(Ai::Confused {
+							previous_ai: Box::new(old_ai),
+							num_turns: turns,
+						});
+						game.log.add(
+								format!("The {} is hit with a sudden jolt of confusion. It starts wandering aimlessly.", objects[id].name),
+								colors::LIGHT_GREEN);
+						took_effect = true;
+					}
+				}
+			}
+			Effect::Identify => {
+				if let Some(inventory_id) = target.item {
+					let item = &objects[caster_id].inventory[inventory_id];
+					let kind = item.item.as_ref().map_or(String::new(), |item| item.kind.clone());
+					let old_name = item.display_name(game);
+					if game.identify(&kind) {
+						game.log.add(format!("The {} is a {}.", old_name, kind), colors::LIGHT_CYAN);
+						took_effect = true;
+					} else {
+						game.log.add("You already know what that is.", colors::WHITE);
+					}
+				}
+			}
+			Effect::Summon { ref monster, count } => {
+				let template = data.monsters.iter().find(|template| &template.name == monster)
+					.expect("summoned monsters are checked on load");
+				let whole_map = Rect::new(0, 0, MAP_WIDTH - 1, MAP_HEIGHT - 1);
+				for _ in 0..count {
+					if let Some((x, y)) = free_spot_near(target.x, target.y, whole_map, &game.map, objects) {
+						let mut summoned = create_monster(x, y, template, game.dungeon_level, data);
+						summoned.faction = objects[caster_id].faction;
+						game.log.add(format!("A {} appears!", summoned.name), colors::LIGHT_VIOLET);
+						objects.push(summoned);
+						took_effect = true;
+					}
+				}
+			}
+			Effect::Nourish { amount, sick_chance } => {
+				for id in affected_objects(target, 0, objects) {
+					if objects[id].nutrition.is_none() {
+						continue;
+					}
+					took_effect = true;
+					if rand::thread_rng().gen_range(0, 100) < sick_chance {
+						game.log.add("Ugh, that was rotten! You feel sick.", colors::LIGHT_GREEN);
+						objects[id].take_damage(FOOD_POISONING_DAMAGE, None, game);
+						continue;
+					}
+					let nutrition = objects[id].nutrition.unwrap();
+					objects[id].nutrition = Some(cmp::min(nutrition + amount, MAX_NUTRITION));
+					game.log.add("That really hit the spot.", colors::LIGHT_VIOLET);
+				}
+			}
+			Effect::LearnSpell { ref spell } => {
+				// only the player keeps a spellbook
+				if caster_id != PLAYER {
+					continue;
+				}
+				if objects[PLAYER].max_mana == 0 {
+					// the book is kept for someone who can use it
+					game.log.add("The book is full of magic you have no mana to cast.", colors::WHITE);
+				} else if game.spells.contains(spell) {
+					game.log.add(format!("You already know how to cast {}.", spell), colors::WHITE);
+				} else if game.spells.len() >= MAX_SPELLS {
+					game.log.add("You can't keep any more spells in your head.", colors::WHITE);
+				} else {
+					game.spells.push(spell.clone());
+					game.log.add(format!("You have learned to cast {}.", spell), colors::LIGHT_CYAN);
+					took_effect = true;
+				}
+			}
+			Effect::Recharge { amount } => {
+				if let Some(inventory_id) = target.item {
+					let name = objects[caster_id].inventory[inventory_id].display_name(game);
+					match objects[caster_id].inventory[inventory_id].item {
+						Some(Item { charges: Some(ref mut charges), .. }) => {
+							*charges += amount;
+							game.log.add(format!("The {} hums with new power.", name), colors::LIGHT_CYAN);
+							took_effect = true;
+						}
+						_ => {
+							game.log.add(format!("The {} can't be recharged.", name), colors::WHITE);
+						}
+					}
 				}
 			}
 		}
 	}
-	objects[PLAYER].fighter.as_mut().unwrap().xp += xp_to_gain;
 
-	UseResult::UsedUp
+	if let Some(fighter) = objects[caster_id].fighter.as_mut() {
+		fighter.xp += xp_to_gain;
+	}
+	took_effect
 }
+
 fn closest_monster(max_range: i32, objects: &mut [Object], tcod: &Tcod) -> Option<usize> {
 	let mut closest_enemy = None;
 	let mut closest_dist = (max_range + 1) as f32; // starat with slightly more than max range
 	for (id, object) in objects.iter().enumerate() {
 		if (id != PLAYER) && object.fighter.is_some() && object.ai.is_some() &&
+		  objects[PLAYER].faction.is_hostile_to(object.faction) &&
 		  tcod.fov.is_in_fov(object.x, object.y)
 		 {
 		 	let dist = objects[PLAYER].distance_to(object);
@@ -1241,92 +3621,150 @@ fn closest_monster(max_range: i32, objects: &mut [Object], tcod: &Tcod) -> Optio
 	closest_enemy
 }	
 
-fn create_item(x: i32, y: i32) -> Object {
-	use Item::*;
-
-	let item_chances = &mut [
-		Weighted {weight: 70, item: Heal},
-		Weighted {weight: 1000, item: Equipment},
-		Weighted {weight: 10, item: Lightning},
-		Weighted {weight: 10, item: Fireball},
-		Weighted {weight: 10, item: Confuse},
-	];
-	let item_choice = WeightedChoice::new(item_chances);
-
-	let item = match item_choice.ind_sample(&mut rand::thread_rng()) {
-		Heal => {
-			let mut object = Object::new(x, y, '!', colors::VIOLET, "healing potion", false);
-			object.item = Some(Heal);
-			object
-		}
-		Lightning => {
-			let mut object = Object::new(x, y, '#', colors::LIGHT_YELLOW, "scroll of lightning bolt", false);
-			object.item = Some(Lightning);
-			object
-		}
-		Confuse => {
-			let mut object = Object::new(x, y, '#', colors::PURPLE, "scroll of confusion", false);
-			object.item = Some(Confuse);
-			object
-		}
-		Fireball => {
-			let mut object = Object::new(x, y, '#', colors::RED, "scroll of fireball", false);
-			object.item = Some(Fireball);
-			object
-		}
-		Equipment => {
-			let mut object = Object::new(x, y, '/', colors::SKY, "sword", false);
-			object.item = Some(Equipment);
-			object.equipment = Some(::Equipment{equipped: false, slot: Slot::RightHand, power_bonus: 3});
-			object
-		}
-	};
-	item
+fn create_item(x: i32, y: i32, level: u32, data: &Data) -> Object {
+	let template = choose_item(level, data, |_| true).expect("item rarity tables are validated on load");
+	create_item_from_template(template, x, y)
+}
+
+// pick a random item template, limited to the ones allowed by the filter
+fn choose_item<F: Fn(&ItemTemplate) -> bool>(level: u32, data: &Data, allowed: F) -> Option<&ItemTemplate> {
+	let templates = data.items.iter().filter(|template| allowed(template)).collect::<Vec<_>>();
+	let rarity_tables = templates.iter().map(|template| &template.rarity[..]).collect::<Vec<_>>();
+	choose_by_level(&rarity_tables, level).map(|index| templates[index])
+}
+
+fn create_item_from_template(template: &ItemTemplate, x: i32, y: i32) -> Object {
+	let mut object = Object::new(x, y, template.char, template.color, &template.name, false);
+	object.item = Some(Item {
+		kind: template.name.clone(),
+		targeting: template.targeting.clone(),
+		effects: template.effects.clone(),
+		message: template.message.clone(),
+		stackable: template.stackable,
+		count: template.count.as_ref().map_or(1, |count| count.roll()),
+		plural: template.plural.clone().unwrap_or_else(|| format!("{}s", template.name)),
+		charges: template.charges.as_ref().map(|charges| charges.roll()),
+		class: template.class,
+		ammo: template.ammo,
+		price: template.price,
+		unpaid: false,
+	});
+	object.equipment = template.equipment;
+	object
 }
 
-// return the position of the tile left-clicked in the player's FOV
-// (None, None) if right-clicked
+// Lets the player pick a tile in view, either with the mouse or by moving a
+// cursor with the movement keys, while Tab jumps between the enemies in range.
+// What's under the cursor is described, and tiles out of range and the area
+// an effect of the given radius would cover are shown.
 fn target_tile(tcod: &mut Tcod,
-			   game: &mut Game,
-	           objects: &[Object],
-	           max_range: Option<f32>)
-	           -> Option<(i32, i32)>
+               game: &mut Game,
+               objects: &[Object],
+               max_range: Option<f32>,
+               radius: i32)
+               -> Option<(i32, i32)>
 {
-	use tcod::input::KeyCode::Escape;
+	use tcod::input::KeyCode::{Escape, Enter, NumPadEnter, Tab};
+
+	let in_range = |x, y| max_range.is_none_or(|range| objects[PLAYER].distance(x, y) <= range);
+	// the enemies Tab goes through, nearest first
+	let mut enemies: Vec<&Object> = objects.iter()
+		.skip(1)
+		.filter(|object| {
+			object.alive && object.fighter.is_some() && objects[PLAYER].faction.is_hostile_to(object.faction) &&
+				tcod.fov.is_in_fov(object.x, object.y) && in_range(object.x, object.y)
+		})
+		.collect();
+	enemies.sort_by(|a, b| {
+		let player = &objects[PLAYER];
+		player.distance_to(a).partial_cmp(&player.distance_to(b)).unwrap_or(cmp::Ordering::Equal)
+	});
+	let mut cursor = enemies.first().map_or(objects[PLAYER].pos(), |enemy| enemy.pos());
+	let mut next_enemy = 1;
 	loop {
-		tcod.root.flush();
-		let event = input::check_for_event(input::KEY_PRESS | input::MOUSE).map(|e| e.1);
-		let mut key = None;
-		match event {
-			Some(Event::Mouse(m)) => tcod.mouse = m,
-			Some(Event::Key(k)) => key = Some(k),
-			None => {}
-		}
 		render_all(tcod, game, objects, false);
+		show_targeting(tcod, game, objects, cursor, max_range, radius);
+		tcod.root.flush();
 
-		let (x, y) = (tcod.mouse.cx as i32, tcod.mouse.cy as i32);
+		let mut confirmed = false;
+		match input::check_for_event(input::KEY_PRESS | input::MOUSE).map(|e| e.1) {
+			Some(Event::Mouse(m)) => {
+				tcod.mouse = m;
+				let (x, y) = (m.cx as i32, m.cy as i32);
+				if x < MAP_WIDTH && y < MAP_HEIGHT {
+					cursor = (x, y);
+				}
+				if m.rbutton_pressed {
+					return None;
+				}
+				confirmed = m.lbutton_pressed;
+			}
+			Some(Event::Key(key)) => match key.code {
+				Escape => return None,
+				Enter | NumPadEnter if !key.alt => confirmed = true,
+				Tab => if !enemies.is_empty() {
+					cursor = enemies[next_enemy % enemies.len()].pos();
+					next_enemy += 1;
+				},
+				_ => if let Some((dx, dy)) = tcod.keymap.command(key).and_then(|command| command.direction()) {
+					cursor = ((cursor.0 + dx).clamp(0, MAP_WIDTH - 1),
+					          (cursor.1 + dy).clamp(0, MAP_HEIGHT - 1));
+				},
+			},
+			_ => {}
+		}
 
-		let in_fov = (x < MAP_WIDTH) && (y < MAP_HEIGHT) && tcod.fov.is_in_fov(x, y);
-		let in_range = max_range.map_or(true, |range| objects[PLAYER].distance(x, y) <= range);
-		if tcod.mouse.lbutton_pressed && in_fov && in_range {
-			return Some((x, y))
+		let (x, y) = cursor;
+		if confirmed && tcod.fov.is_in_fov(x, y) && in_range(x, y) {
+			return Some(cursor)
 		}
+	}
+}
 
-		let escape = key.map_or(false, |k| k.code == Escape);
-		if tcod.mouse.rbutton_pressed || escape {
-			return None
+// Draws the aiming aids over the map, with a line saying what's under the cursor
+fn show_targeting(tcod: &mut Tcod,
+                  game: &Game,
+                  objects: &[Object],
+                  cursor: (i32, i32),
+                  max_range: Option<f32>,
+                  radius: i32)
+{
+	let (cursor_x, cursor_y) = cursor;
+	for y in 0..MAP_HEIGHT {
+		for x in 0..MAP_WIDTH {
+			if !game.map[x as usize][y as usize].explored {
+				continue;
+			}
+			let distance = ((x - cursor_x).pow(2) as f32 + (y - cursor_y).pow(2) as f32).sqrt();
+			if radius > 0 && distance <= radius as f32 {
+				tcod.root.set_char_background(x, y, COLOR_AREA_OF_EFFECT, BackgroundFlag::Set);
+			} else if max_range.is_some_and(|range| objects[PLAYER].distance(x, y) > range) {
+				tcod.root.set_char_background(x, y, COLOR_OUT_OF_RANGE, BackgroundFlag::Multiply);
+			}
 		}
 	}
+	tcod.root.set_char_background(cursor_x, cursor_y, COLOR_CURSOR, BackgroundFlag::Set);
+
+	let mut description = describe_tile(cursor_x, cursor_y, objects, &tcod.fov, game);
+	if max_range.is_some_and(|range| objects[PLAYER].distance(cursor_x, cursor_y) > range) {
+		description.push_str(" (out of range)");
+	}
+	// over the line that usually names what's under the mouse
+	tcod.root.set_default_background(colors::BLACK);
+	tcod.root.rect(0, PANEL_Y, SCREEN_WIDTH, 1, true, BackgroundFlag::Set);
+	tcod.root.set_default_foreground(colors::LIGHT_GREY);
+	tcod.root.print_ex(1, PANEL_Y, BackgroundFlag::None, TextAlignment::Left, description);
 }
 
 fn target_monster(tcod: &mut Tcod,
 			      game: &mut Game,
 	              objects: &[Object],
-	              max_range: Option<f32>)
+	              max_range: Option<f32>,
+	              radius: i32)
 	              -> Option<usize>
 {
 	loop {
-		match target_tile(tcod, game, objects, max_range) {
+		match target_tile(tcod, game, objects, max_range, radius) {
 			Some((x, y)) => {
 				// return the first clicked monster, otherwise continue looping
 				for (id, obj) in objects.iter().enumerate() {
@@ -1340,20 +3778,476 @@ fn target_monster(tcod: &mut Tcod,
 	}
 }
 
-fn drop_item(inventory_id: usize, objects: &mut Vec<Object>, game: &mut Game) {
-	let mut item = game.inventory.remove(inventory_id);
-	if item.equipment.is_some() {
-		item.dequip(&mut game.log);
-	}
-	item.set_pos(objects[PLAYER].x, objects[PLAYER].y);
-	game.log.add(format!("You dropped a {}.", item.name), colors::YELLOW);
-	objects.push(item);
+// Follow a line towards the target until a wall or something solid gets in the way.
+// Returns where the missile ends up and what it hit.
+fn missile_path(from: (i32, i32), to: (i32, i32), map: &Map, objects: &[Object]) -> ((i32, i32), Option<usize>) {
+	let mut landing = from;
+	for (x, y) in Line::new(from, to) {
+		if map[x as usize][y as usize].blocked {
+			break;
+		}
+		landing = (x, y);
+		let hit = objects.iter().position(|object| object.pos() == (x, y) && object.blocks);
+		if hit.is_some() {
+			return (landing, hit);
+		}
+	}
+	(landing, None)
+}
+
+// Send something flying from the player towards a tile. Potions shatter where they
+// land, anything else hurts whoever it hits and ends up on the floor.
+fn launch(mut missile: Object, target: (i32, i32), power: i32, objects: &mut Vec<Object>, game: &mut Game, data: &Data) {
+	if missile.equipment.is_some_and(|e| e.equipped) {
+		missile.dequip(&mut game.log);
+		objects[PLAYER].heal(0);
+	}
+	let name = missile.display_name(game);
+	let ((x, y), hit) = missile_path(objects[PLAYER].pos(), target, &game.map, objects);
+	missile.set_pos(x, y);
+
+	let item = missile.item.clone().unwrap();
+	if item.class == Some(ItemClass::Potion) {
+		game.log.add(format!("The {} shatters!", name), colors::LIGHT_BLUE);
+		let target = Target::tile(x, y, objects);
+		if apply_effects(&item.effects, &target, PLAYER, objects, game, data) && game.identify(&item.kind) {
+			game.log.add(format!("That must have been a {}.", item.kind), colors::LIGHT_CYAN);
+		}
+		return;
+	}
+
+	if let Some(id) = hit {
+		let damage = power - objects[id].defense();
+		if damage > 0 {
+			game.log.add(format!("The {} hits the {} for {} hp.", name, objects[id].name, damage), colors::WHITE);
+			if let Some(xp) = objects[id].take_damage(damage, Some(Faction::Player), game) {
+				objects[PLAYER].fighter.as_mut().unwrap().xp += xp;
+			}
+		} else {
+			game.log.add(format!("The {} bounces off the {}.", name, objects[id].name), colors::WHITE);
+		}
+	}
+	place_on_floor(missile, objects);
+}
+
+// shoot the equipped bow or sling, returns true if a shot was taken
+fn fire_launcher(objects: &mut Vec<Object>, game: &mut Game, tcod: &mut Tcod) -> bool {
+	let launcher = objects[PLAYER].get_all_equipped().iter().filter_map(|e| e.launcher).next();
+	let launcher = match launcher {
+		Some(launcher) => launcher,
+		None => {
+			game.log.add("You have nothing to shoot with.", colors::WHITE);
+			return false;
+		}
+	};
+	let ammo_id = objects[PLAYER].inventory.iter().position(|object| {
+		object.item.as_ref().is_some_and(|item| item.ammo == Some(launcher.ammo))
+	});
+	let ammo_id = match ammo_id {
+		Some(id) => id,
+		None => {
+			game.log.add("You have nothing to shoot.", colors::WHITE);
+			return false;
+		}
+	};
+	game.log.add(TARGET_TILE_HELP, colors::LIGHT_CYAN);
+	match target_tile(tcod, game, objects, Some(launcher.range as f32), 0) {
+		Some(target) => {
+			let missile = split_stack(ammo_id, 1, &mut objects[PLAYER].inventory);
+			launch(missile, target, launcher.power, objects, game, &tcod.data);
+			true
+		}
+		None => false,
+	}
+}
+
+// buy the unpaid goods the player is carrying, or sell things to the shopkeeper
+fn shop_menu(keeper_id: usize, objects: &mut Vec<Object>, game: &mut Game, tcod: &mut Tcod) {
+	let header = format!("\"Welcome, stranger! What can I do for you?\"\nYou have {} gold.\n", objects[PLAYER].gold);
+	let choice = menu(&header, &["Buy what I'm carrying", "Sell something"], INVENTORY_WIDTH, &mut tcod.root);
+	match choice {
+		Some(0) => buy_item(keeper_id, objects, game, tcod),
+		Some(1) => sell_item(keeper_id, objects, game, tcod),
+		_ => {}
+	}
+}
+
+fn buy_item(keeper_id: usize, objects: &mut [Object], game: &mut Game, tcod: &mut Tcod) {
+	let unpaid = objects[PLAYER].inventory.iter()
+		.enumerate()
+		.filter(|&(_, object)| object.item.as_ref().is_some_and(|item| item.unpaid))
+		.map(|(id, _)| id)
+		.collect::<Vec<_>>();
+	if unpaid.is_empty() {
+		game.log.add("You aren't carrying anything from the shop.", colors::WHITE);
+		return;
+	}
+	let options = unpaid.iter()
+		.map(|&id| objects[PLAYER].inventory[id].display_name(game))
+		.collect::<Vec<_>>();
+	let choice = menu("Press the key next to an item to pay for it, or any other to cancel.\n",
+	                  &options, INVENTORY_WIDTH, &mut tcod.root);
+	let inventory_id = match choice {
+		Some(choice) => unpaid[choice],
+		None => return,
+	};
+
+	let price = objects[PLAYER].inventory[inventory_id].item.as_ref().map_or(0, |item| item.price * item.count);
+	if objects[PLAYER].gold < price {
+		game.log.add("You can't afford that.", colors::RED);
+		return;
+	}
+	objects[PLAYER].gold -= price;
+	objects[keeper_id].gold += price;
+	objects[PLAYER].inventory[inventory_id].item.as_mut().unwrap().unpaid = false;
+	let name = objects[PLAYER].inventory[inventory_id].display_name(game);
+	game.log.add(format!("You bought {} for {} gold.", name, price), colors::GOLD);
+}
+
+fn sell_item(keeper_id: usize, objects: &mut Vec<Object>, game: &mut Game, tcod: &mut Tcod) {
+	let inventory_id = inventory_menu(
+		&objects[PLAYER].inventory,
+		game,
+		"Press the key next to an item to sell it, or any other to cancel.\n",
+		&mut tcod.root);
+	let inventory_id = match inventory_id {
+		Some(id) => id,
+		None => return,
+	};
+	let (price, unpaid) = objects[PLAYER].inventory[inventory_id].item.as_ref().map_or((0, false), |item| (item.price, item.unpaid));
+	// the shop pays half of what it charges
+	let offer = price / 2;
+	if unpaid {
+		game.log.add("\"That's mine already!\"", colors::YELLOW);
+		return;
+	}
+	if offer <= 0 {
+		game.log.add("\"I've no use for that.\"", colors::YELLOW);
+		return;
+	}
+	if objects[keeper_id].gold < offer {
+		game.log.add("\"I can't afford that, I'm afraid.\"", colors::YELLOW);
+		return;
+	}
+
+	let room = match objects[keeper_id].ai {
+		Some(Ai::Shopkeeper { room }) => room,
+		_ => return,
+	};
+	let mut item = split_stack(inventory_id, 1, &mut objects[PLAYER].inventory);
+	if item.equipment.is_some() {
+		item.dequip(&mut game.log);
+		objects[PLAYER].heal(0);
+	}
+	objects[PLAYER].gold += offer;
+	objects[keeper_id].gold -= offer;
+	game.log.add(format!("You sold {} for {} gold.", item.display_name(game), offer), colors::GOLD);
+
+	// it goes out on display with the rest of the stock
+	let (x, y) = objects[keeper_id].pos();
+	let (x, y) = free_spot_near(x, y, room, &game.map, objects).unwrap_or(objects[PLAYER].pos());
+	item.set_pos(x, y);
+	item.item.as_mut().unwrap().unpaid = true;
+	place_on_floor(item, objects);
+}
+
+// Leaving a shop with unpaid goods is theft. Once there is no shopkeeper left to
+// mind them, unpaid goods belong to whoever has them.
+fn check_for_theft(objects: &mut [Object], game: &mut Game) {
+	let (x, y) = objects[PLAYER].pos();
+	let carrying_unpaid = objects[PLAYER].inventory.iter().any(|object| {
+		object.item.as_ref().is_some_and(|item| item.unpaid)
+	});
+	for keeper in objects.iter_mut() {
+		if let Some(Ai::Shopkeeper { room }) = keeper.ai {
+			if carrying_unpaid && !room.contains(x, y) {
+				game.log.add(format!("You stole from the {}!", keeper.name), colors::RED);
+				keeper.provoke(game);
+			}
+		}
+	}
+
+	// the goods are only free for the taking once no shopkeeper is left alive
+	if objects.iter().any(|object| object.keeps_shop && object.alive) {
+		return;
+	}
+	for object in objects.iter_mut() {
+		if let Some(ref mut item) = object.item {
+			item.unpaid = false;
+		}
+		for carried in &mut object.inventory {
+			if let Some(ref mut item) = carried.item {
+				item.unpaid = false;
+			}
+		}
+	}
+}
+
+fn show_journal(game: &Game, root: &mut Root) {
+	if game.quests.is_empty() {
+		msgbox("Quest journal\n\nYou haven't taken on any quests.\n", JOURNAL_WIDTH, root);
+		return;
+	}
+	let mut text = String::from("Quest journal\n");
+	// unfinished quests first
+	let quests = game.quests.iter().filter(|quest| !quest.completed)
+		.chain(game.quests.iter().filter(|quest| quest.completed));
+	for quest in quests {
+		let status = if quest.completed {
+			"done".to_string()
+		} else {
+			format!("{}/{}", cmp::min(quest.progress, quest.objective.goal()), quest.objective.goal())
+		};
+		text.push_str(&format!("\n{} ({})\n{}\nObjective: {}\n",
+		                       quest.name, status, quest.description, quest.objective.describe()));
+	}
+	msgbox(&text, JOURNAL_WIDTH, root);
+}
+
+// Every message the log still has, scrolled with the keys for moving up and
+// down, page up and down, home and end. '/' searches back from the newest,
+// 'n' finds the next match further back.
+fn show_message_history(game: &Game, keymap: &Keymap, root: &mut Root) {
+	use tcod::input::KeyCode::{PageUp, PageDown, Home, End, Escape};
+
+	let messages = &game.log.messages;
+	// one line for the title and one for the controls
+	let height = SCREEN_HEIGHT - 2;
+	let mut window = Offscreen::new(SCREEN_WIDTH, SCREEN_HEIGHT);
+	let heights: Vec<i32> = messages.iter()
+		.map(|message| window.get_height_rect(0, 0, SCREEN_WIDTH, 0, message.text()))
+		.collect();
+	// scrolled all the way down, the newest messages fill the screen
+	let mut last_top = messages.len();
+	let mut used = 0;
+	while last_top > 0 && used + heights[last_top - 1] <= height {
+		used += heights[last_top - 1];
+		last_top -= 1;
+	}
+	let mut top = last_top;
+	let mut search: Option<String> = None;
+	let mut found: Option<usize> = None;
+	let mut status = String::new();
+	loop {
+		window.set_default_background(colors::BLACK);
+		window.clear();
+		window.set_default_foreground(colors::WHITE);
+		window.print_ex(0, 0, BackgroundFlag::None, TextAlignment::Left, "Message history");
+
+		let mut y = 1;
+		for (index, message) in messages.iter().enumerate().skip(top) {
+			if y + heights[index] > 1 + height {
+				break;
+			}
+			// matches are highlighted, the one searched to the most
+			let matches = search.as_ref().is_some_and(|search| matches_search(&message.text, search));
+			let background = if Some(index) == found {
+				window.set_default_background(colors::DARK_BLUE);
+				BackgroundFlag::Set
+			} else if matches {
+				window.set_default_background(colors::DARKEST_BLUE);
+				BackgroundFlag::Set
+			} else {
+				BackgroundFlag::None
+			};
+			window.set_default_foreground(message.color);
+			window.print_rect_ex(0, y, SCREEN_WIDTH, 0, background, TextAlignment::Left, message.text());
+			y += heights[index];
+		}
+
+		window.set_default_background(colors::BLACK);
+		window.set_default_foreground(colors::LIGHT_GREY);
+		let controls = if status.is_empty() {
+			"Up/down, PgUp/PgDn, Home/End: scroll  /: search  n: next match  Esc: close".to_string()
+		} else {
+			status.clone()
+		};
+		window.print_ex(0, SCREEN_HEIGHT - 1, BackgroundFlag::None, TextAlignment::Left, controls);
+		blit(&window, (0, 0), (SCREEN_WIDTH, SCREEN_HEIGHT), root, (0, 0), 1.0, 1.0);
+		root.flush();
+
+		let key = root.wait_for_keypress(true);
+		status.clear();
+		let page = height as usize / 2;
+		let mut find_next = false;
+		match (keymap.command(key), key.code, key.printable) {
+			(Some(Command::North), _, _) => top = top.saturating_sub(1),
+			(Some(Command::South), _, _) => top = cmp::min(top + 1, last_top),
+			(_, PageUp, _) => top = top.saturating_sub(page),
+			(_, PageDown, _) => top = cmp::min(top + page, last_top),
+			(_, Home, _) => top = 0,
+			(_, End, _) => top = last_top,
+			(_, Escape, _) => break,
+			(_, _, '/') => {
+				search = input_text("Search the messages for:", JOURNAL_WIDTH, root)
+					.map(|text| text.trim().to_string())
+					.and_then(|text| if text.is_empty() { None } else { Some(text) });
+				found = None;
+				find_next = true;
+			}
+			(_, _, 'n') => find_next = true,
+			_ => {}
+		}
+		if find_next {
+			if let Some(ref search) = search {
+				// look further back than the last match, or from the newest message
+				let before = found.unwrap_or(messages.len());
+				match (0..before).rev().find(|&index| matches_search(&messages[index].text, search)) {
+					Some(index) => {
+						found = Some(index);
+						top = cmp::min(index, last_top);
+					}
+					None => status = format!("No more messages with \"{}\".", search),
+				}
+			}
+		}
+	}
+}
+
+fn matches_search(text: &str, search: &str) -> bool {
+	text.to_lowercase().contains(&search.to_lowercase())
+}
+
+// hold a conversation with an NPC until it runs out or the player walks away
+fn talk(npc_id: usize, objects: &mut Vec<Object>, game: &mut Game, tcod: &mut Tcod) {
+	let name = match objects[npc_id].dialogue {
+		Some(ref name) => name.clone(),
+		None => return,
+	};
+	let dialogue = match tcod.data.dialogues.iter().find(|dialogue| dialogue.name == name) {
+		Some(dialogue) => dialogue,
+		None => return,
+	};
+	let mut node = &dialogue.nodes[0];
+	loop {
+		let choices = node.choices.iter()
+			.filter(|choice| choice.conditions.iter().all(|condition| condition_holds(condition, objects, game)))
+			.collect::<Vec<_>>();
+		let header = format!("{}:\n\"{}\"\n", objects[npc_id].name, node.text);
+		let options = choices.iter().map(|choice| choice.text.as_str()).collect::<Vec<_>>();
+		let choice = match menu(&header, &options, DIALOGUE_WIDTH, &mut tcod.root) {
+			Some(choice) => choices[choice],
+			None => return,
+		};
+		for action in &choice.actions {
+			run_dialogue_action(action, npc_id, objects, game, &tcod.data);
+		}
+		node = match choice.next {
+			Some(ref next) => dialogue.nodes.iter().find(|node| &node.id == next).expect("dialogue is checked on load"),
+			None => return,
+		};
+	}
+}
+
+fn condition_holds(condition: &Condition, objects: &[Object], game: &Game) -> bool {
+	match *condition {
+		Condition::Flag(ref flag) => game.flags.contains(flag),
+		Condition::NotFlag(ref flag) => !game.flags.contains(flag),
+		Condition::HasItem(ref kind) => objects[PLAYER].inventory.iter().any(|object| {
+			object.item.as_ref().is_some_and(|item| &item.kind == kind)
+		}),
+		Condition::HasGold(amount) => objects[PLAYER].gold >= amount,
+		Condition::QuestStarted(ref name) => game.quest(name).is_some(),
+		Condition::QuestCompleted(ref name) => game.quest(name).is_some_and(|quest| quest.completed),
+		Condition::Not(ref condition) => !condition_holds(condition, objects, game),
+	}
+}
+
+fn run_dialogue_action(action: &DialogueAction, npc_id: usize, objects: &mut Vec<Object>, game: &mut Game, data: &Data) {
+	match *action {
+		DialogueAction::SetFlag(ref flag) => {
+			game.flags.insert(flag.clone());
+		}
+		DialogueAction::GiveItem(ref name) => {
+			let template = data.items.iter().find(|item| &item.name == name).expect("dialogue is checked on load");
+			game.log.add(format!("The {} hands you something.", objects[npc_id].name), colors::GREEN);
+			give_item(template, objects, game);
+		}
+		DialogueAction::StartQuest(ref name) => {
+			if game.quest(name).is_none() {
+				let template = data.quests.iter().find(|quest| &quest.name == name).expect("dialogue is checked on load");
+				game.log.add(format!("New quest: {}", template.name), colors::LIGHT_YELLOW);
+				game.quests.push(Quest {
+					name: template.name.clone(),
+					description: template.description.clone(),
+					objective: template.objective.clone(),
+					reward: template.reward.clone(),
+					progress: 0,
+					completed: false,
+				});
+				// the depth reached so far already counts, and so does what the player carries
+				let level = game.dungeon_level;
+				game.quest_event(QuestEvent::Reached(level));
+				for object in &objects[PLAYER].inventory {
+					if let Some(ref item) = object.item {
+						game.quest_event(QuestEvent::PickedUp(&item.kind));
+					}
+				}
+			}
+		}
+		DialogueAction::TakeItem(ref kind) => {
+			let inventory_id = objects[PLAYER].inventory.iter().position(|object| {
+				object.item.as_ref().is_some_and(|item| &item.kind == kind)
+			});
+			if let Some(inventory_id) = inventory_id {
+				let mut item = split_stack(inventory_id, 1, &mut objects[PLAYER].inventory);
+				if item.equipment.is_some() {
+					item.dequip(&mut game.log);
+					objects[PLAYER].heal(0);
+				}
+				game.log.add(format!("You hand over {}.", item.display_name(game)), colors::WHITE);
+			}
+		}
+		DialogueAction::GiveGold(amount) => {
+			objects[PLAYER].gold += amount;
+			game.log.add(format!("You receive {} gold.", amount), colors::GOLD);
+		}
+		DialogueAction::TakeGold(amount) => {
+			let amount = cmp::min(amount, objects[PLAYER].gold);
+			objects[PLAYER].gold -= amount;
+			game.log.add(format!("You hand over {} gold.", amount), colors::GOLD);
+		}
+		DialogueAction::TurnHostile => {
+			let npc = &mut objects[npc_id];
+			game.log.add(format!("The {} turns on you!", npc.name), colors::RED);
+			npc.turn_hostile();
+		}
+	}
+}
+
+// put a new item from a template in the player's pack, or at their feet if it's full
+fn give_item(template: &ItemTemplate, objects: &mut Vec<Object>, game: &mut Game) {
+	let (x, y) = objects[PLAYER].pos();
+	let item = create_item_from_template(template, x, y);
+	game.log.add(format!("You receive {}.", item.display_name(game)), colors::GREEN);
+	let stacks = objects[PLAYER].inventory.iter().any(|object| object.stacks_with(&item));
+	if objects[PLAYER].inventory.len() < 26 || stacks {
+		// a gift counts for quests the same as something picked up
+		game.quest_event(QuestEvent::PickedUp(&template.name));
+		add_to_inventory(item, &mut objects[PLAYER].inventory);
+	} else {
+		game.log.add("Your inventory is full, so it goes on the floor.", colors::RED);
+		place_on_floor(item, objects);
+	}
+}
+
+fn drop_item(inventory_id: usize, amount: i32, objects: &mut Vec<Object>, game: &mut Game) {
+	let mut item = split_stack(inventory_id, amount, &mut objects[PLAYER].inventory);
+	if item.equipment.is_some() {
+		item.dequip(&mut game.log);
+		objects[PLAYER].heal(0);
+	}
+	item.set_pos(objects[PLAYER].x, objects[PLAYER].y);
+	if amount > 1 {
+		game.log.add(format!("You dropped {}.", item.display_name(game)), colors::YELLOW);
+	} else {
+		game.log.add(format!("You dropped a {}.", item.display_name(game)), colors::YELLOW);
+	}
+	place_on_floor(item, objects);
 }
 
 fn main_menu(tcod: &mut Tcod) {
-	let img = tcod::image::Image::from_file("menu_background.png")
-		.ok()
-		.expect("Background image not found");
+	let img = tcod::image::Image::from_file("menu_background.png").expect("Background image not found");
 
 	while !tcod.root.window_closed() {
 		tcod::image::blit_2x(&img, (0, 0), (-1, -1), &mut tcod.root, (0, 0));
@@ -1371,8 +4265,9 @@ fn main_menu(tcod: &mut Tcod) {
 
 		match choice {
 			Some(0) => {
-				let (mut objects, mut game) = new_game(tcod);
-				play_game(&mut objects, &mut game, tcod);
+				if let Some((mut objects, mut game)) = new_game(tcod) {
+					play_game(&mut objects, &mut game, tcod);
+				}
 			}
 			Some(1) => {
 				// load game
@@ -1396,19 +4291,62 @@ fn main_menu(tcod: &mut Tcod) {
 	}
 }
 
+// Let the player type a line of text. Returns None if they press escape.
+fn input_text(header: &str, width: i32, root: &mut Root) -> Option<String> {
+	use tcod::input::KeyCode::{Enter, Escape, Backspace};
+
+	let mut text = String::new();
+	loop {
+		let prompt = format!("{}\n> {}_", header, text);
+		let height = root.get_height_rect(0, 0, width, SCREEN_HEIGHT, &prompt);
+		let mut window = Offscreen::new(width, height);
+		window.set_default_foreground(colors::WHITE);
+		window.print_rect_ex(0, 0, width, height, BackgroundFlag::None, TextAlignment::Left, &prompt);
+
+		let x = SCREEN_WIDTH / 2 - width / 2;
+		let y = SCREEN_HEIGHT / 2 - height / 2;
+		blit(&window, (0, 0), (width, height), root, (x, y), 1.0, 0.7);
+		root.flush();
+
+		let key = root.wait_for_keypress(true);
+		match key.code {
+			Enter => return Some(text),
+			Escape => return None,
+			Backspace => {
+				text.pop();
+			}
+			_ if key.printable != '\0' && !key.printable.is_control() => text.push(key.printable),
+			_ => {}
+		}
+	}
+}
+
+// ask how many of a stack to use, an empty answer means all of them
+fn ask_quantity(question: &str, max: i32, root: &mut Root) -> Option<i32> {
+	let header = format!("{} (1-{}, enter for all)", question, max);
+	let answer = input_text(&header, INVENTORY_WIDTH, root)?;
+	if answer.trim().is_empty() {
+		return Some(max);
+	}
+	match answer.trim().parse::<i32>() {
+		Ok(amount) if amount > 0 => Some(cmp::min(amount, max)),
+		_ => None,
+	}
+}
+
 fn msgbox(text: &str, width: i32, root: &mut Root) {
     let options: &[&str] = &[];
     menu(text, options, width, root);
 }
 
-fn save_game(objects: &[Object], game: &Game) -> Result<(), Box<Error>> {
+fn save_game(objects: &[Object], game: &Game) -> Result<(), Box<dyn Error>> {
 	let save_data = serde_json::to_string(&(objects, game))?;
 	let mut file = File::create("savegame")?;
 	file.write_all(save_data.as_bytes())?;
 	Ok(())
 }
 
-fn load_game() -> Result<(Vec<Object>, Game), Box<Error>> {
+fn load_game() -> Result<(Vec<Object>, Game), Box<dyn Error>> {
 	let mut json_save_state = String::new();
 	let mut file = File::open("savegame")?;
 	file.read_to_string(&mut json_save_state)?;
@@ -1416,46 +4354,911 @@ fn load_game() -> Result<(Vec<Object>, Game), Box<Error>> {
 	Ok(result)
 }
 
+#[derive(Debug, Deserialize)]
+struct MonsterTemplate {
+	name: String,
+	char: char,
+	color: Color,
+	fighter: FighterTemplate,
+	ai: Ai,
+	xp: i32,
+	// spawn weight by dungeon level
+	spawn: Vec<Transition>,
+	pack: Range,
+	// percent chance of spawning with a weapon
+	#[serde(default)]
+	weapon_chance: u32,
+	// how much gold it carries
+	gold: Option<Range>,
+	faction: Faction,
+	dialogue: Option<String>,
+	// unique monsters turn up once per run, somewhere between these levels
+	unique: Option<Range>,
+	// logged the first time the player sees a unique monster
+	announcement: Option<String>,
+	// items it always carries, and drops when it dies
+	#[serde(default)]
+	loot: Vec<String>,
+	#[serde(default)]
+	abilities: Vec<Ability>,
+}
+
+#[derive(Debug, Deserialize)]
+struct FighterTemplate {
+	max_hp: i32,
+	defense: i32,
+	power: i32,
+}
+
+// a random number between min and max, inclusive
+#[derive(Debug, Deserialize)]
+struct Range {
+	min: i32,
+	max: i32,
+}
+
+impl Range {
+	fn roll(&self) -> i32 {
+		rand::thread_rng().gen_range(self.min, self.max + 1)
+	}
+
+	fn is_valid(&self) -> bool {
+		0 <= self.min && self.min <= self.max
+	}
+}
+
+#[derive(Debug, Deserialize)]
+struct ItemTemplate {
+	name: String,
+	char: char,
+	color: Color,
+	// spawn weight by dungeon level
+	rarity: Vec<Transition>,
+	#[serde(default)]
+	stackable: bool,
+	// defaults to the name with an "s" on the end
+	plural: Option<String>,
+	#[serde(default)]
+	targeting: Targeting,
+	#[serde(default)]
+	effects: Vec<Effect>,
+	// shown when the item is used
+	message: Option<String>,
+	equipment: Option<Equipment>,
+	// potions, scrolls, wands and staves start out unidentified
+	class: Option<ItemClass>,
+	// wands and staves start with this many charges
+	charges: Option<Range>,
+	// how many are found together, for stackable items
+	count: Option<Range>,
+	ammo: Option<Ammo>,
+	// items without a price can't be bought or sold
+	#[serde(default)]
+	price: i32,
+}
+
+// A conversation, starting at its first node. Each choice the player picks can
+// run some actions and lead on to another node, or end the conversation.
+#[derive(Debug, Deserialize)]
+struct Dialogue {
+	name: String,
+	nodes: Vec<DialogueNode>,
+}
+
+#[derive(Debug, Deserialize)]
+struct DialogueNode {
+	id: String,
+	text: String,
+	#[serde(default)]
+	choices: Vec<DialogueChoice>,
+}
+
+#[derive(Debug, Deserialize)]
+struct DialogueChoice {
+	text: String,
+	next: Option<String>,
+	// only offered when all of these hold
+	#[serde(default)]
+	conditions: Vec<Condition>,
+	#[serde(default)]
+	actions: Vec<DialogueAction>,
+}
+
+#[derive(Debug, Deserialize)]
+enum Condition {
+	Flag(String),
+	NotFlag(String),
+	// the player carries an item of this kind
+	HasItem(String),
+	HasGold(i32),
+	QuestStarted(String),
+	QuestCompleted(String),
+	Not(Box<Condition>),
+}
+
+#[derive(Debug, Deserialize)]
+enum DialogueAction {
+	SetFlag(String),
+	GiveItem(String),
+	TakeItem(String),
+	GiveGold(i32),
+	TakeGold(i32),
+	StartQuest(String),
+	// the speaker turns on the player
+	TurnHostile,
+}
+
+// What the player starts out as. The class decides the starting stats and
+// kit, which stats can be raised on leveling up, and the skills it can use.
+#[derive(Debug, Deserialize)]
+struct ClassTemplate {
+	name: String,
+	description: String,
+	fighter: FighterTemplate,
+	#[serde(default)]
+	gold: i32,
+	// names of the items it starts with
+	#[serde(default)]
+	items: Vec<String>,
+	level_up: Vec<StatGain>,
+	#[serde(default)]
+	skills: Vec<Skill>,
+	#[serde(default)]
+	mana: i32,
+	#[serde(default)]
+	spells: Vec<SpellGrant>,
+}
+
+// how much xp each level takes, and the perks to be had along the way
+#[derive(Debug, Deserialize)]
+struct Progression {
+	xp: XpCurve,
+	// a perk is chosen on every level that's a multiple of this
+	perk_every: i32,
+	perks: Vec<Perk>,
+}
+
+#[derive(Debug, Deserialize)]
+struct XpCurve {
+	base: i32,
+	factor: i32,
+	#[serde(default)]
+	growth: i32,
+}
+
+impl XpCurve {
+	// xp needed to get from this level to the next
+	fn to_next_level(&self, level: i32) -> i32 {
+		self.base + level * self.factor + level * level * self.growth
+	}
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+struct Perk {
+	name: String,
+	description: String,
+	// the lowest character level it can be picked at
+	level: i32,
+	// the perk before this one in its tree
+	requires: Option<String>,
+	// limited to these classes, or open to all when empty
+	#[serde(default)]
+	classes: Vec<String>,
+	bonus: PerkBonus,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+enum PerkBonus {
+	// percent chance of attacking twice
+	DoubleStrike { chance: u32 },
+	// percent less damage of this kind
+	Resist { kind: DamageKind, percent: i32 },
+	// recover twice as fast
+	QuickRegen,
+	// a new skill to use
+	Skill(Skill),
+}
+
+// a spell the class learns on reaching a level, 1 being the start
+#[derive(Debug, Deserialize)]
+struct SpellGrant {
+	spell: String,
+	level: i32,
+}
+
+// cast from the spellbook for mana, and aimed and worked like an item
+#[derive(Clone, Debug, Deserialize)]
+struct Spell {
+	name: String,
+	mana: i32,
+	targeting: Targeting,
+	effects: Vec<Effect>,
+	message: String,
+}
+
+// one of the choices on leveling up
+#[derive(Debug, Deserialize)]
+struct StatGain {
+	name: String,
+	#[serde(default)]
+	max_hp: i32,
+	#[serde(default)]
+	power: i32,
+	#[serde(default)]
+	defense: i32,
+	#[serde(default)]
+	mana: i32,
+}
+
+impl StatGain {
+	// like "Constitution (+20 HP, from 100)"
+	fn describe(&self, player: &Object) -> String {
+		let fighter = player.fighter.expect("only fighters level up");
+		let mut gains = vec![];
+		if self.max_hp != 0 {
+			gains.push(format!("{:+} HP, from {}", self.max_hp, fighter.max_hp));
+		}
+		if self.power != 0 {
+			gains.push(format!("{:+} attack, from {}", self.power, fighter.base_power));
+		}
+		if self.defense != 0 {
+			gains.push(format!("{:+} defense, from {}", self.defense, fighter.defense));
+		}
+		if self.mana != 0 {
+			gains.push(format!("{:+} mana, from {}", self.mana, player.max_mana));
+		}
+		format!("{} ({})", self.name, gains.join("; "))
+	}
+}
+
+// a class's special move, which needs a rest before it can be used again
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+struct Skill {
+	name: String,
+	targeting: Targeting,
+	effects: Vec<Effect>,
+	message: String,
+	// turns before it can be used again
+	#[serde(default)]
+	cooldown: i32,
+}
+
+#[derive(Debug, Deserialize)]
+struct QuestTemplate {
+	name: String,
+	description: String,
+	objective: Objective,
+	#[serde(default)]
+	reward: Reward,
+}
+
+fn load_data() -> Result<Data, String> {
+	let monsters = load_json::<Vec<MonsterTemplate>>(MONSTERS_FILE)?;
+	validate_monsters(&monsters).map_err(|e| format!("{}: {}", MONSTERS_FILE, e))?;
+	let items = load_json::<Vec<ItemTemplate>>(ITEMS_FILE)?;
+	validate_items(&items).map_err(|e| format!("{}: {}", ITEMS_FILE, e))?;
+	for item in &items {
+		for effect in &item.effects {
+			if let Err(problem) = validate_summon(effect, &monsters) {
+				return Err(format!("{}: item \"{}\": {}", ITEMS_FILE, item.name, problem));
+			}
+		}
+	}
+	for monster in &monsters {
+		for effect in monster.abilities.iter().flat_map(|ability| &ability.effects) {
+			if let Err(problem) = validate_summon(effect, &monsters) {
+				return Err(format!("{}: monster \"{}\": {}", MONSTERS_FILE, monster.name, problem));
+			}
+		}
+		for loot in &monster.loot {
+			if !items.iter().any(|item| &item.name == loot) {
+				return Err(format!("{}: monster \"{}\": no item called \"{}\"", MONSTERS_FILE, monster.name, loot));
+			}
+		}
+	}
+
+	let quests = load_json::<Vec<QuestTemplate>>(QUESTS_FILE)?;
+	validate_quests(&quests, &monsters, &items).map_err(|e| format!("{}: {}", QUESTS_FILE, e))?;
+	let dialogues = load_json::<Vec<Dialogue>>(DIALOGUE_FILE)?;
+	validate_dialogues(&dialogues, &items, &quests).map_err(|e| format!("{}: {}", DIALOGUE_FILE, e))?;
+	let spells = load_json::<Vec<Spell>>(SPELLS_FILE)?;
+	validate_spells(&spells).map_err(|e| format!("{}: {}", SPELLS_FILE, e))?;
+	for spell in &spells {
+		for effect in &spell.effects {
+			if let Err(problem) = validate_summon(effect, &monsters) {
+				return Err(format!("{}: spell \"{}\": {}", SPELLS_FILE, spell.name, problem));
+			}
+		}
+	}
+	for item in &items {
+		for effect in &item.effects {
+			if let Effect::LearnSpell { ref spell } = *effect {
+				if !spells.iter().any(|other| &other.name == spell) {
+					return Err(format!("{}: item \"{}\": no spell called \"{}\"", ITEMS_FILE, item.name, spell));
+				}
+			}
+		}
+	}
+	let classes = load_json::<Vec<ClassTemplate>>(CLASSES_FILE)?;
+	validate_classes(&classes, &items, &spells).map_err(|e| format!("{}: {}", CLASSES_FILE, e))?;
+	let progression = load_json::<Progression>(PROGRESSION_FILE)?;
+	validate_progression(&progression, &classes).map_err(|e| format!("{}: {}", PROGRESSION_FILE, e))?;
+	for perk in &progression.perks {
+		if let PerkBonus::Skill(ref skill) = perk.bonus {
+			for effect in &skill.effects {
+				if let Err(problem) = validate_summon(effect, &monsters) {
+					return Err(format!("{}: perk \"{}\": {}", PROGRESSION_FILE, perk.name, problem));
+				}
+			}
+		}
+	}
+	for class in &classes {
+		for effect in class.skills.iter().flat_map(|skill| &skill.effects) {
+			if let Err(problem) = validate_summon(effect, &monsters) {
+				return Err(format!("{}: class \"{}\": {}", CLASSES_FILE, class.name, problem));
+			}
+		}
+	}
+	if !monsters.iter().any(|monster| monster.name == FINAL_BOSS) {
+		return Err(format!("{}: the final boss \"{}\" is missing", MONSTERS_FILE, FINAL_BOSS));
+	}
+	if !items.iter().any(|item| item.name == ARTIFACT) {
+		return Err(format!("{}: the artifact \"{}\" is missing", ITEMS_FILE, ARTIFACT));
+	}
+	for monster in &monsters {
+		if let Some(ref dialogue) = monster.dialogue {
+			if !dialogues.iter().any(|other| &other.name == dialogue) {
+				return Err(format!("{}: monster \"{}\": no dialogue called \"{}\"", MONSTERS_FILE, monster.name, dialogue));
+			}
+		}
+	}
+
+	Ok(Data {
+		monsters,
+		items,
+		dialogues,
+		quests,
+		classes,
+		spells,
+		progression,
+	})
+}
+
+// summoned monsters have to exist, and uniques can't be summoned
+fn validate_summon(effect: &Effect, monsters: &[MonsterTemplate]) -> Result<(), String> {
+	if let Effect::Summon { ref monster, .. } = *effect {
+		match monsters.iter().find(|template| &template.name == monster) {
+			None => return Err(format!("no monster called \"{}\"", monster)),
+			Some(template) if template.unique.is_some() => {
+				return Err(format!("\"{}\" is unique and can't be summoned", monster));
+			}
+			_ => {}
+		}
+	}
+	Ok(())
+}
+
+fn load_json<T: DeserializeOwned>(path: &str) -> Result<T, String> {
+	let mut contents = String::new();
+	File::open(path)
+		.and_then(|mut file| file.read_to_string(&mut contents))
+		.map_err(|e| format!("{}: {}", path, e))?;
+	serde_json::from_str(&contents).map_err(|e| format!("{}: {}", path, e))
+}
+
+// Spawn tables are step functions, so checking the level each step starts
+// at is enough to know every level has something to spawn
+fn validate_spawn_table(tables: &[&[Transition]]) -> Result<(), String> {
+	let mut levels = vec![1];
+	for table in tables {
+		if table.windows(2).any(|pair| pair[0].level >= pair[1].level) {
+			return Err("spawn levels must be in increasing order".into());
+		}
+		levels.extend(table.iter().map(|transition| transition.level));
+	}
+	for level in levels {
+		let total: u32 = tables.iter().map(|table| from_dungeon_level(table, level)).sum();
+		if total == 0 {
+			return Err(format!("nothing can spawn on dungeon level {}", level));
+		}
+	}
+	Ok(())
+}
+
+fn validate_monsters(monsters: &[MonsterTemplate]) -> Result<(), String> {
+	let mut names = HashSet::new();
+	for monster in monsters {
+		let fail = |problem: &str| Err(format!("monster \"{}\": {}", monster.name, problem));
+		if monster.name.is_empty() {
+			return Err("every monster needs a name".into());
+		}
+		if !names.insert(&monster.name) {
+			return fail("defined more than once");
+		}
+		if monster.fighter.max_hp <= 0 {
+			return fail("max_hp must be positive");
+		}
+		if monster.pack.min < 1 || !monster.pack.is_valid() {
+			return fail("pack size needs 1 <= min <= max");
+		}
+		if monster.weapon_chance > 100 {
+			return fail("weapon_chance is a percentage, 0 to 100");
+		}
+		if monster.ai != Ai::Basic {
+			return fail("only \"Basic\" ai can be given in data");
+		}
+		if monster.gold.as_ref().is_some_and(|gold| !gold.is_valid()) {
+			return fail("gold needs 0 <= min <= max");
+		}
+		if let Some(ref depths) = monster.unique {
+			if depths.min < 1 || !depths.is_valid() {
+				return fail("unique levels need 1 <= min <= max");
+			}
+			if !monster.spawn.is_empty() {
+				return fail("unique monsters can't have a spawn table");
+			}
+		} else if monster.announcement.is_some() {
+			return fail("only unique monsters are announced");
+		}
+		for ability in &monster.abilities {
+			if ability.chance == 0 || ability.chance > 100 {
+				return fail("ability chance is a percentage, 1 to 100");
+			}
+			if ability.targeting == Targeting::InventoryItem {
+				return fail("monsters can't target items");
+			}
+			if let Err(problem) = validate_ability(&ability.targeting, &ability.effects) {
+				return fail(problem);
+			}
+		}
+	}
+	let spawn_tables = monsters.iter().map(|monster| &monster.spawn[..]).collect::<Vec<_>>();
+	validate_spawn_table(&spawn_tables)
+}
+
+// Monster abilities and class skills work like items without the item, so
+// they can't do anything that needs one
+fn validate_ability(targeting: &Targeting, effects: &[Effect]) -> Result<(), &'static str> {
+	if *targeting == Targeting::InventoryItem {
+		return Err("only items can target other items");
+	}
+	validate_targeting(targeting)?;
+	for effect in effects {
+		match *effect {
+			Effect::Identify | Effect::Recharge { .. } | Effect::Nourish { .. } | Effect::LearnSpell { .. } => {
+				return Err("abilities can only heal, damage, confuse or summon");
+			}
+			_ => validate_effect(effect)?,
+		}
+	}
+	Ok(())
+}
+
+fn validate_progression(progression: &Progression, classes: &[ClassTemplate]) -> Result<(), String> {
+	let xp = &progression.xp;
+	if xp.base <= 0 || xp.factor < 0 || xp.growth < 0 {
+		return Err("the xp curve needs a positive base and no negative factor or growth".into());
+	}
+	if progression.perk_every < 1 {
+		return Err("perk_every must be at least 1".into());
+	}
+	let mut names = HashSet::new();
+	for perk in &progression.perks {
+		let fail = |problem: String| Err(format!("perk \"{}\": {}", perk.name, problem));
+		if !names.insert(&perk.name) {
+			return fail("defined more than once".into());
+		}
+		if perk.level < 1 {
+			return fail("level must be at least 1".into());
+		}
+		if let Some(ref required) = perk.requires {
+			match progression.perks.iter().find(|other| &other.name == required) {
+				None => return fail(format!("no perk called \"{}\"", required)),
+				Some(other) if other.level > perk.level => {
+					return fail(format!("comes before \"{}\", which it needs", required));
+				}
+				_ => {}
+			}
+		}
+		for class in &perk.classes {
+			if !classes.iter().any(|template| &template.name == class) {
+				return fail(format!("no class called \"{}\"", class));
+			}
+		}
+		match perk.bonus {
+			PerkBonus::DoubleStrike { chance } if chance == 0 || chance > 100 => {
+				return fail("double strike chance is a percentage, 1 to 100".into());
+			}
+			PerkBonus::Resist { percent, .. } if percent <= 0 || percent > 100 => {
+				return fail("resistance is a percentage, 1 to 100".into());
+			}
+			PerkBonus::Skill(ref skill) => {
+				if skill.cooldown < 0 {
+					return fail("skill cooldown can't be negative".into());
+				}
+				if let Err(problem) = validate_ability(&skill.targeting, &skill.effects) {
+					return fail(problem.into());
+				}
+			}
+			_ => {}
+		}
+	}
+	// the perk and skill menus only have a letter for 26 choices
+	for class in classes {
+		let open = progression.perks.iter()
+			.filter(|perk| perk.classes.is_empty() || perk.classes.contains(&class.name))
+			.collect::<Vec<_>>();
+		if open.len() > 26 {
+			return Err(format!("the {} class could be offered {} perks at once, at most 26 fit", class.name, open.len()));
+		}
+		let skills = class.skills.len() +
+			open.iter().filter(|perk| matches!(perk.bonus, PerkBonus::Skill(_))).count();
+		if skills > 26 {
+			return Err(format!("the {} class could end up with {} skills, at most 26 fit", class.name, skills));
+		}
+	}
+	Ok(())
+}
+
+fn validate_spells(spells: &[Spell]) -> Result<(), String> {
+	let mut names = HashSet::new();
+	for spell in spells {
+		let fail = |problem: &str| Err(format!("spell \"{}\": {}", spell.name, problem));
+		if !names.insert(&spell.name) {
+			return fail("defined more than once");
+		}
+		if spell.mana <= 0 {
+			return fail("mana cost must be positive");
+		}
+		if let Err(problem) = validate_ability(&spell.targeting, &spell.effects) {
+			return fail(problem);
+		}
+	}
+	Ok(())
+}
+
+fn validate_classes(classes: &[ClassTemplate], items: &[ItemTemplate], spells: &[Spell]) -> Result<(), String> {
+	if classes.is_empty() {
+		return Err("there has to be at least one class".into());
+	}
+	// the class menu only has a letter for 26 choices
+	if classes.len() > 26 {
+		return Err(format!("{} classes won't fit in the class menu, at most 26 do", classes.len()));
+	}
+	let mut names = HashSet::new();
+	for class in classes {
+		let fail = |problem: String| Err(format!("class \"{}\": {}", class.name, problem));
+		if !names.insert(&class.name) {
+			return fail("defined more than once".into());
+		}
+		if class.fighter.max_hp <= 0 {
+			return fail("max_hp must be positive".into());
+		}
+		if class.gold < 0 {
+			return fail("gold can't be negative".into());
+		}
+		if class.mana < 0 {
+			return fail("mana can't be negative".into());
+		}
+		if class.spells.len() > MAX_SPELLS {
+			return fail(format!("can't be granted more than {} spells", MAX_SPELLS));
+		}
+		for grant in &class.spells {
+			if !spells.iter().any(|spell| spell.name == grant.spell) {
+				return fail(format!("no spell called \"{}\"", grant.spell));
+			}
+			if grant.level < 1 {
+				return fail(format!("spell \"{}\" has to be learned at level 1 or later", grant.spell));
+			}
+		}
+		for item in &class.items {
+			if !items.iter().any(|template| &template.name == item) {
+				return fail(format!("no item called \"{}\"", item));
+			}
+		}
+		if class.level_up.is_empty() {
+			return fail("needs at least one level up choice".into());
+		}
+		if class.level_up.len() > 26 {
+			return fail("has more level up choices than fit in the menu, at most 26 do".into());
+		}
+		for gain in &class.level_up {
+			if gain.max_hp < 0 || gain.power < 0 || gain.defense < 0 || gain.mana < 0 ||
+			gain.max_hp + gain.power + gain.defense + gain.mana == 0 {
+				return fail(format!("level up choice \"{}\" has to raise something", gain.name));
+			}
+		}
+		let mut skills = HashSet::new();
+		for skill in &class.skills {
+			if !skills.insert(&skill.name) {
+				return fail(format!("skill \"{}\" defined more than once", skill.name));
+			}
+			if skill.cooldown < 0 {
+				return fail(format!("skill \"{}\": cooldown can't be negative", skill.name));
+			}
+			if let Err(problem) = validate_ability(&skill.targeting, &skill.effects) {
+				return fail(format!("skill \"{}\": {}", skill.name, problem));
+			}
+		}
+	}
+	Ok(())
+}
+
+fn validate_quests(quests: &[QuestTemplate], monsters: &[MonsterTemplate], items: &[ItemTemplate]) -> Result<(), String> {
+	let mut names = HashSet::new();
+	for quest in quests {
+		let fail = |problem: String| Err(format!("quest \"{}\": {}", quest.name, problem));
+		if !names.insert(&quest.name) {
+			return fail("defined more than once".into());
+		}
+		match quest.objective {
+			Objective::Kill { ref monster, count } => {
+				if !monsters.iter().any(|template| &template.name == monster) {
+					return fail(format!("no monster called \"{}\"", monster));
+				}
+				if count <= 0 {
+					return fail("kill count must be positive".into());
+				}
+			}
+			Objective::Retrieve { ref item } if !items.iter().any(|template| &template.name == item) => {
+				return fail(format!("no item called \"{}\"", item));
+			}
+			Objective::ReachDepth { level } if level < 2 => {
+				return fail("the depth to reach must be below the first level".into());
+			}
+			_ => {}
+		}
+		for item in &quest.reward.items {
+			if !items.iter().any(|template| &template.name == item) {
+				return fail(format!("no item called \"{}\"", item));
+			}
+		}
+	}
+	Ok(())
+}
+
+fn validate_dialogues(dialogues: &[Dialogue], items: &[ItemTemplate], quests: &[QuestTemplate]) -> Result<(), String> {
+	let mut names = HashSet::new();
+	for dialogue in dialogues {
+		let fail = |problem: String| Err(format!("dialogue \"{}\": {}", dialogue.name, problem));
+		if !names.insert(&dialogue.name) {
+			return fail("defined more than once".into());
+		}
+		if dialogue.nodes.is_empty() {
+			return fail("needs at least one node".into());
+		}
+		let ids = dialogue.nodes.iter().map(|node| &node.id).collect::<HashSet<_>>();
+		if ids.len() != dialogue.nodes.len() {
+			return fail("node ids must be unique".into());
+		}
+		for choice in dialogue.nodes.iter().flat_map(|node| &node.choices) {
+			if let Some(ref next) = choice.next {
+				if !ids.contains(next) {
+					return fail(format!("no node called \"{}\"", next));
+				}
+			}
+			for action in &choice.actions {
+				match *action {
+					DialogueAction::GiveItem(ref name) | DialogueAction::TakeItem(ref name)
+						if !items.iter().any(|item| &item.name == name) => {
+							return fail(format!("no item called \"{}\"", name));
+						}
+					DialogueAction::GiveGold(amount) | DialogueAction::TakeGold(amount) if amount <= 0 => {
+						return fail("gold amounts must be positive".into());
+					}
+					DialogueAction::StartQuest(ref name) if !quests.iter().any(|quest| &quest.name == name) => {
+						return fail(format!("no quest called \"{}\"", name));
+					}
+					_ => {}
+				}
+			}
+			let mut conditions = choice.conditions.iter().collect::<Vec<_>>();
+			while let Some(condition) = conditions.pop() {
+				match *condition {
+					Condition::Not(ref condition) => conditions.push(condition),
+					Condition::QuestStarted(ref name) | Condition::QuestCompleted(ref name)
+						if !quests.iter().any(|quest| &quest.name == name) => {
+							return fail(format!("no quest called \"{}\"", name));
+						}
+					_ => {}
+				}
+			}
+		}
+	}
+	Ok(())
+}
+
+fn validate_effect(effect: &Effect) -> Result<(), &'static str> {
+	match *effect {
+		Effect::Heal { amount } if amount <= 0 => Err("heal amount must be positive"),
+		Effect::Damage { amount, .. } if amount <= 0 => Err("damage amount must be positive"),
+		Effect::Recharge { amount } if amount <= 0 => Err("recharge amount must be positive"),
+		Effect::Nourish { amount, .. } if amount <= 0 => Err("food must be worth something"),
+		Effect::Summon { count, .. } if count <= 0 => Err("summon count must be positive"),
+		Effect::Nourish { sick_chance, .. } if sick_chance > 100 => Err("sick chance is a percentage"),
+		Effect::Status { turns, .. } if turns <= 0 => Err("status effects must last at least one turn"),
+		Effect::Damage { radius, .. } | Effect::Status { radius, .. } if radius < 0 => Err("radius can't be negative"),
+		_ => Ok(()),
+	}
+}
+
+fn validate_targeting(targeting: &Targeting) -> Result<(), &'static str> {
+	let range_ok = match *targeting {
+		Targeting::User | Targeting::InventoryItem => true,
+		Targeting::ClosestMonster { range } => range > 0,
+		Targeting::Monster { range } | Targeting::Tile { range } => range.is_none_or(|range| range > 0.0),
+	};
+	if range_ok { Ok(()) } else { Err("targeting range must be positive") }
+}
+
+fn validate_items(items: &[ItemTemplate]) -> Result<(), String> {
+	let mut names = HashSet::new();
+	for item in items {
+		let fail = |problem: &str| Err(format!("item \"{}\": {}", item.name, problem));
+		if item.name.is_empty() {
+			return Err("every item needs a name".into());
+		}
+		if !names.insert(&item.name) {
+			return fail("defined more than once");
+		}
+		if item.equipment.is_some() && !item.effects.is_empty() {
+			return fail("equipment can't also have effects");
+		}
+		if item.equipment.is_some() && item.stackable {
+			return fail("equipment can't be stackable");
+		}
+		if let Err(problem) = validate_targeting(&item.targeting) {
+			return fail(problem);
+		}
+		for effect in &item.effects {
+			if let Err(problem) = validate_effect(effect) {
+				return fail(problem);
+			}
+		}
+		let needs_item = item.effects.iter()
+			.any(|effect| matches!(*effect, Effect::Identify | Effect::Recharge { .. }));
+		if needs_item && item.targeting != Targeting::InventoryItem {
+			return fail("identifying and recharging need \"InventoryItem\" targeting");
+		}
+		if item.class.is_some() && item.equipment.is_some() {
+			return fail("equipment is always identified, it can't have a class");
+		}
+		if item.name == GOLD_KIND {
+			return fail("gold is built in");
+		}
+		if item.price < 0 {
+			return fail("price can't be negative");
+		}
+		if let Some(ref count) = item.count {
+			if count.min < 1 || !count.is_valid() || !item.stackable {
+				return fail("count needs 1 <= min <= max and a stackable item");
+			}
+		}
+		let launcher = item.equipment.and_then(|equipment| equipment.launcher);
+		if launcher.is_some_and(|launcher| launcher.range <= 0 || launcher.power < 0) {
+			return fail("launchers need a positive range and power");
+		}
+		if let Some(ref charges) = item.charges {
+			if !charges.is_valid() {
+				return fail("charges need 0 <= min <= max");
+			}
+			if item.stackable || item.equipment.is_some() {
+				return fail("only plain items can have charges");
+			}
+		}
+	}
+	let potions = items.iter().filter(|item| item.class == Some(ItemClass::Potion)).count();
+	if potions > POTION_LOOKS.len() {
+		return Err(format!("only {} kinds of potion can be told apart", POTION_LOOKS.len()));
+	}
+	let rods = items.iter().filter(|item| {
+		item.class == Some(ItemClass::Wand) || item.class == Some(ItemClass::Staff)
+	}).count();
+	if rods > WAND_LOOKS.len() {
+		return Err(format!("only {} kinds of wand and staff can be told apart", WAND_LOOKS.len()));
+	}
+	let rarity_tables = items.iter().map(|item| &item.rarity[..]).collect::<Vec<_>>();
+	validate_spawn_table(&rarity_tables)
+}
+
+// Level up as many times as the player's xp allows, picking a stat to raise
+// each time and a perk on the levels that grant one
 fn level_up(objects: &mut [Object], game: &mut Game, tcod: &mut Tcod) {
 	let player = &mut objects[PLAYER];
-	let level_up_xp = LEVEL_UP_BASE + player.level * LEVEL_UP_FACTOR;
-	if player.fighter.map_or(0, |f| f.xp) >= level_up_xp {
+	loop {
+		let level_up_xp = tcod.data.progression.xp.to_next_level(player.level);
+		if player.fighter.map_or(0, |f| f.xp) < level_up_xp {
+			break;
+		}
 		player.level += 1;
 		game.log.add(format!("You leveled up to {}!", player.level), colors::YELLOW);
 
-		// pick stats to increase
-		let fighter = player.fighter.as_mut().unwrap();
+		// pick stats to increase, from the ones the class allows
+		let options = &tcod.data.classes.iter()
+			.find(|class| class.name == game.class)
+			.expect("the player's class comes from the class data")
+			.level_up;
+		let descriptions = options.iter().map(|option| option.describe(player)).collect::<Vec<_>>();
 		let mut choice = None;
 		while choice.is_none() {  // keep asking until a choice is made
 		    choice = menu(
-		        "Level up! Choose a stat to raise:\n",
-		        &[format!("Constitution (+20 HP, from {})", fighter.max_hp),
-		          format!("Strength (+1 attack, from {})", fighter.base_power),
-		          format!("Agility (+1 defense, from {})", fighter.defense)],
+		        &format!("Level {}! Choose a stat to raise:\n", player.level),
+		        &descriptions,
 		        LEVEL_SCREEN_WIDTH, &mut tcod.root);
 		};
+		let option = &options[choice.unwrap()];
+		player.max_mana += option.mana;
+		player.mana += option.mana;
+		let fighter = player.fighter.as_mut().unwrap();
 		fighter.xp -= level_up_xp;
-		match choice.unwrap() {
-			0 => {
-				fighter.max_hp += 20;
-				fighter.hp += 20;
-			}
-			1 => {
-				fighter.base_power += 1;
-			}
-			2 => {
-				fighter.defense += 1;
-			}
-			_ => unreachable!(),
+		fighter.max_hp += option.max_hp;
+		fighter.hp += option.max_hp;
+		fighter.base_power += option.power;
+		fighter.defense += option.defense;
+		let level = player.level;
+		learn_class_spells(level, game, &tcod.data);
+		if level % tcod.data.progression.perk_every == 0 {
+			choose_perk(level, game, tcod);
 		}
 	}
 }
 
+fn choose_perk(level: i32, game: &mut Game, tcod: &mut Tcod) {
+	// perks further down a tree need the one before them
+	let perks = tcod.data.progression.perks.iter().filter(|perk| {
+		perk.level <= level && !game.has_perk(&perk.name) &&
+			perk.requires.as_ref().is_none_or(|required| game.has_perk(required)) &&
+			(perk.classes.is_empty() || perk.classes.contains(&game.class))
+	}).collect::<Vec<_>>();
+	if perks.is_empty() {
+		return;
+	}
+	let options = perks.iter().map(|perk| format!("{}: {}", perk.name, perk.description)).collect::<Vec<_>>();
+	let mut choice = None;
+	while choice.is_none() {
+		choice = menu("Choose a perk:\n", &options, PERK_SCREEN_WIDTH, &mut tcod.root);
+	}
+	let perk = perks[choice.unwrap()].clone();
+	game.log.add(format!("You gained the {} perk.", perk.name), colors::YELLOW);
+	game.perks.push(perk);
+}
+
 fn get_equipped_in_slot(slot: Slot, inventory: &[Object]) -> Option<usize> {
 	for (inventory_id, item) in inventory.iter().enumerate() {
-		if item.equipment.as_ref().map_or(false, |e| e.equipped && e.slot == slot) {
+		if item.equipment.as_ref().is_some_and(|e| e.equipped && e.slots().contains(&slot)) {
 			return Some(inventory_id)
 		}
 	}
 	None
+}
+
+// true if the equipment can be put on without taking anything else off
+fn slot_is_free(equipment: Equipment, inventory: &[Object]) -> bool {
+	let free = equipment.slots().iter().all(|&slot| get_equipped_in_slot(slot, inventory).is_none());
+	let alternative_free = equipment.slot.alternative().is_some_and(|slot| {
+		get_equipped_in_slot(slot, inventory).is_none()
+	});
+	free || alternative_free
+}
+
+// equip an item, first taking off anything worn in the slots it needs
+fn equip_item(inventory_id: usize, inventory: &mut [Object], log: &mut Messages) {
+	let mut equipment = match inventory[inventory_id].equipment {
+		Some(equipment) => equipment,
+		None => return inventory[inventory_id].equip(log),
+	};
+	// put rings on the free hand if there is one
+	if let Some(alternative) = equipment.slot.alternative() {
+		if get_equipped_in_slot(equipment.slot, inventory).is_some() &&
+			get_equipped_in_slot(alternative, inventory).is_none() {
+			equipment.slot = alternative;
+			inventory[inventory_id].equipment = Some(equipment);
+		}
+	}
+	for slot in equipment.slots() {
+		if let Some(old_equipment) = get_equipped_in_slot(slot, inventory) {
+			inventory[old_equipment].dequip(log);
+		}
+	}
+	inventory[inventory_id].equip(log);
 }
\ No newline at end of file